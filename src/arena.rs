@@ -1,38 +1,165 @@
 #![allow(dead_code)]
 
 use core::str;
-use std::{alloc::{Layout, alloc, dealloc}, cell::Cell, fmt::Debug, marker::PhantomData, ops::{Deref, DerefMut}, ptr::{self, copy_nonoverlapping}, slice::{self, from_raw_parts, from_raw_parts_mut}};
+use std::{alloc::{Layout, alloc, dealloc}, cell::{Cell, RefCell}, fmt::Debug, marker::PhantomData, ops::{Deref, DerefMut, Index, IndexMut}, ptr::{self, NonNull, copy_nonoverlapping}, slice::{self, SliceIndex, from_raw_parts, from_raw_parts_mut}};
 
 #[inline]
 fn array<T>(n: usize) -> Layout {
     Layout::array::<T>(n).unwrap()
 }
 
+/// Nothing in an arena is ever dropped, so types that would need to be are rejected when compiling instead
+/// of silently leaking whatever they own.
+#[inline]
+fn assert_no_drop<T>() {
+    const { assert!(!std::mem::needs_drop::<T>(), "types that need to be dropped can't be allocated in an arena") }
+}
+
 // let the user of this function enforce the lifetime of the &str returned
 #[inline]
 unsafe fn ptr_to_string_mut(ptr: *mut u8, len: usize) -> &'static mut str {
     unsafe { str::from_utf8_unchecked_mut(from_raw_parts_mut(ptr, len)) }
 }
 
-pub struct Arena<'a> {
+/// Returned when an allocation would take an arena past its memory limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfMemory {
+    pub requested: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Allocating {} bytes would exceed the memory limit of {} bytes", self.requested, self.limit)
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+const CHUNK_ALIGN: usize = 16;
+
+/// A position in an arena, see `Arena::checkpoint`. It borrows the arena, so that the arena can't be reset while
+/// there's a checkpoint to go back to.
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint<'c> {
+    /// the arena the checkpoint was made in, only compared against
+    arena: *const (),
+    chunk: usize,
+    n: usize,
+    top: usize,
+    used: usize,
+    padding: usize,
+    marker: PhantomData<&'c ()>,
+}
+
+/// How an arena is being used, see `Arena::stats`. `peak` and `copies` are kept across resets.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// bytes handed out, including what vectors left behind when they moved to grow
+    pub used: usize,
+    /// the most bytes that have been in use at once
+    pub peak: usize,
+    /// bytes skipped to align allocations
+    pub padding: usize,
+    /// bytes allocated across all chunks
+    pub allocated: usize,
+    pub chunks: usize,
+    /// times a vector was copied elsewhere because it couldn't grow in place
+    pub copies: usize,
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes used (peak {}), {} bytes of padding, {} bytes in {} chunk{}, {} vector copies",
+            self.used, self.peak, self.padding, self.allocated, self.chunks,
+            if self.chunks == 1 { "" } else { "s" }, self.copies,
+        )
+    }
+}
+
+struct Chunk {
     memory: *mut u8,
-    top: Cell<usize>, // holds the ptr to the top element. Doesn't need to be derefed so used `usize`
-    n: Cell<usize>,
     layout: Layout,
+}
+
+/// A bump allocator. Memory is handed out from a chunk until it runs out, at which point a new chunk
+/// twice the size is allocated. Chunks are only freed when the arena is dropped, so references stay valid.
+pub struct Arena<'a> {
+    chunks: RefCell<Vec<Chunk>>,
+    memory: Cell<*mut u8>, // start of the current chunk, the last one in `chunks`
+    capacity: Cell<usize>, // size of the current chunk
+    top: Cell<usize>, // holds the ptr to the top element. Doesn't need to be derefed so used `usize`
+    n: Cell<usize>, // bytes used of the current chunk
+    allocated: Cell<usize>, // bytes allocated across all chunks
+    stats: Cell<Stats>,
+    limit: Option<usize>,
     marker: PhantomData<&'a u8>,
 }
 
 impl<'a> Arena<'a> {
+    /// `size` is the size of the first chunk
     pub fn new(size: usize) -> Self {
-        let layout = array::<u8>(size);
-        let memory = unsafe { alloc(layout) };
-        Arena { 
-            memory,
-            top: Cell::new(memory as _),
+        let arena = Arena {
+            chunks: RefCell::new(Vec::new()),
+            memory: Cell::new(ptr::null_mut()),
+            capacity: Cell::new(0),
+            top: Cell::new(0),
             n: Cell::new(0),
-            layout,
+            allocated: Cell::new(0),
+            stats: Cell::new(Stats::default()),
+            limit: None,
             marker: PhantomData,
+        };
+        arena.push_chunk(size.max(1));
+        arena
+    }
+
+    /// caps the memory allocated across all chunks to `limit` bytes, past which allocating fails
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    fn push_chunk(&self, size: usize) {
+        // aligned for most types up front, so that padding only depends on what has been allocated
+        let layout = Layout::from_size_align(size, CHUNK_ALIGN).unwrap();
+        let memory = unsafe { alloc(layout) };
+        if memory.is_null() {
+            std::alloc::handle_alloc_error(layout);
         }
+
+        self.chunks.borrow_mut().push(Chunk { memory, layout });
+        self.memory.set(memory);
+        self.capacity.set(size);
+        self.top.set(memory as _);
+        self.n.set(0);
+        self.allocated.set(self.allocated.get() + size);
+    }
+
+    /// starts a new chunk that fits at least `n` bytes
+    fn grow(&self, n: usize) -> Result<(), OutOfMemory> {
+        let size = (self.capacity.get() * 2).max(n);
+        let Some(limit) = self.limit else {
+            self.push_chunk(size);
+            return Ok(());
+        };
+
+        let remaining = limit.saturating_sub(self.allocated.get());
+        if n > remaining {
+            return Err(OutOfMemory { requested: n, limit });
+        }
+        self.push_chunk(size.min(remaining));
+        Ok(())
+    }
+
+    #[inline]
+    fn record(&self, update: impl FnOnce(&mut Stats)) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        stats.peak = stats.peak.max(stats.used);
+        self.stats.set(stats);
     }
 
     #[inline]
@@ -40,111 +167,243 @@ impl<'a> Arena<'a> {
         self.n.set(self.n.get() + n);
     }
 
-    unsafe fn alloc_bytes(&self, n: usize) -> *mut u8 {
-        if self.n.get() + n > self.layout.size() {
-            panic!("Memoryyyyy");
+    /// bytes needed to bring the top of the current chunk up to `align`
+    #[inline]
+    fn padding(&self, align: usize) -> usize {
+        let next = self.memory.get() as usize + self.n.get();
+        next.wrapping_neg() & (align - 1)
+    }
+
+    /// every allocation goes through here, so that each one is aligned for what is stored in it
+    unsafe fn try_alloc_layout(&self, layout: Layout) -> Result<*mut u8, OutOfMemory> {
+        if self.n.get() + self.padding(layout.align()) + layout.size() > self.capacity.get() {
+            // enough for the worst case padding at the start of the new chunk
+            self.grow(layout.size() + layout.align() - 1)?;
         }
-    
-        let ptr = unsafe { self.memory.add(self.n.get()) };
-        self.advance_by(n);
+
+        let padding = self.padding(layout.align());
+        self.advance_by(padding);
+        let ptr = unsafe { self.memory.get().add(self.n.get()) };
+        self.advance_by(layout.size());
         self.top.set(ptr as usize);
-        ptr
+        self.record(|stats| {
+            stats.used += layout.size();
+            stats.padding += padding;
+        });
+        Ok(ptr)
     }
-    
-    pub fn alloc<T>(&self, item: T) -> &'a mut T {
-        unsafe { 
-            let ptr = self.alloc_bytes(size_of::<T>()) as *mut T;
-            *ptr = item;
-            &mut *ptr
+
+    #[allow(clippy::mut_from_ref)] // each allocation is fresh memory, so it isn't aliased
+    pub fn try_alloc<T>(&'a self, item: T) -> Result<&'a mut T, OutOfMemory> {
+        assert_no_drop::<T>();
+        unsafe {
+            let ptr = self.try_alloc_layout(Layout::new::<T>())? as *mut T;
+            ptr::write(ptr, item);
+            Ok(&mut *ptr)
         }
     }
 
-    pub fn alloc_str(&self, str: &str) -> &'a mut str {
-        unsafe { 
-            let ptr = self.alloc_bytes(str.len());
+    pub fn alloc<T>(&'a self, item: T) -> &'a mut T {
+        self.try_alloc(item).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    #[allow(clippy::mut_from_ref)] // each allocation is fresh memory, so it isn't aliased
+    pub fn try_alloc_str(&'a self, str: &str) -> Result<&'a mut str, OutOfMemory> {
+        unsafe {
+            let ptr = self.try_alloc_layout(array::<u8>(str.len()))?;
             copy_nonoverlapping(str.as_ptr(), ptr, str.len());
-            ptr_to_string_mut(ptr, str.len()) 
+            Ok(ptr_to_string_mut(ptr, str.len()))
         }
     }
 
-    pub fn alloc_slice<T>(&self, slice: &[T]) -> &'a mut [T] {
-        unsafe { 
-            let align = std::mem::align_of::<T>();
-            let padding = align - (self.n.get() % align) % align;
-            self.advance_by(padding);
-            let ptr = self.alloc_bytes(slice.len() * size_of::<T>());
+    pub fn alloc_str(&'a self, str: &str) -> &'a mut str {
+        self.try_alloc_str(str).unwrap_or_else(|err| panic!("{err}"))
+    }
 
-            copy_nonoverlapping(slice.as_ptr(), ptr as _, slice.len() * size_of::<T>());
-            slice::from_raw_parts_mut(ptr as _, slice.len())
+    #[allow(clippy::mut_from_ref)] // each allocation is fresh memory, so it isn't aliased
+    pub fn try_alloc_slice<T>(&'a self, slice: &[T]) -> Result<&'a mut [T], OutOfMemory> {
+        assert_no_drop::<T>();
+        unsafe {
+            let ptr = self.try_alloc_layout(array::<T>(slice.len()))? as *mut T;
+            copy_nonoverlapping(slice.as_ptr(), ptr, slice.len());
+            Ok(slice::from_raw_parts_mut(ptr, slice.len()))
         }
     }
 
-    unsafe fn realloc<T>(&self, ptr: *const T, old_size: usize, new_size: usize) -> *mut T {
-        let old_size = old_size * size_of::<T>();
-        let new_size = new_size * size_of::<T>();
+    pub fn alloc_slice<T>(&'a self, slice: &[T]) -> &'a mut [T] {
+        self.try_alloc_slice(slice).unwrap_or_else(|err| panic!("{err}"))
+    }
 
-        fn inner(me: &Arena, ptr: usize, old_size: usize, new_size: usize) -> usize {
-            if me.top.get() == ptr {
-                me.advance_by(new_size - old_size);
-                return ptr;
-            }
+    /// grows an array of `old_len` `T`s to fit `new_len`, moving it if it can't be extended in place
+    unsafe fn try_realloc<T>(&self, ptr: *const T, old_len: usize, new_len: usize) -> Result<*mut T, OutOfMemory> {
+        let old_size = old_len * size_of::<T>();
+        let new_size = new_len * size_of::<T>();
 
-            unsafe {
-                let new_ptr = me.alloc_bytes(new_size);
-                copy_nonoverlapping(ptr as _, new_ptr, old_size);
-                me.advance_by(new_size);
-                new_ptr as _
+        // the last allocation can grow in place, as long as the chunk has room for it
+        if self.top.get() == ptr as usize && self.n.get() + new_size - old_size <= self.capacity.get() {
+            self.advance_by(new_size - old_size);
+            self.record(|stats| stats.used += new_size - old_size);
+            return Ok(ptr as _);
+        }
+
+        unsafe {
+            let new_ptr = self.try_alloc_layout(array::<T>(new_len))? as *mut T;
+            copy_nonoverlapping(ptr, new_ptr, old_len);
+            if old_len > 0 {
+                self.record(|stats| stats.copies += 1);
             }
+            Ok(new_ptr)
         }
+    }
 
-        inner(&self, ptr as _, old_size, new_size) as _
+    /// bytes allocated across all chunks
+    pub fn allocated(&self) -> usize {
+        self.allocated.get()
     }
 
-    pub fn reset(self) -> Self {
-        unsafe { self.memory.write_bytes(0, self.layout.size()); }
-        self
+    pub fn stats(&self) -> Stats {
+        Stats {
+            allocated: self.allocated.get(),
+            chunks: self.chunks.borrow().len(),
+            ..self.stats.get()
+        }
+    }
+
+    /// Rewinds the arena so its memory can be reused, keeping only the largest chunk. Everything allocated is
+    /// borrowed from the arena for `'a`, so it has to be gone before the arena can be borrowed mutably here.
+    pub fn reset(&mut self) {
+        let chunks = self.chunks.get_mut();
+        let largest = (0..chunks.len()).max_by_key(|&i| chunks[i].layout.size()).unwrap();
+        let largest = chunks.swap_remove(largest);
+        chunks.drain(..).for_each(|chunk| unsafe { dealloc(chunk.memory, chunk.layout) });
+        self.allocated.set(largest.layout.size());
+        let top = largest.memory as usize;
+        chunks.push(largest);
+
+        self.rewind_to(0, top);
+        self.record(|stats| {
+            stats.used = 0;
+            stats.padding = 0;
+        });
+    }
+
+    /// marks the current top of the arena, to go back to with `rollback`
+    pub fn checkpoint(&self) -> Checkpoint<'_> {
+        Checkpoint {
+            arena: self as *const Self as *const (),
+            chunk: self.chunks.borrow().len() - 1,
+            n: self.n.get(),
+            top: self.top.get(),
+            used: self.stats.get().used,
+            padding: self.stats.get().padding,
+            marker: PhantomData,
+        }
+    }
+
+    /// Frees everything allocated since `checkpoint`, while what came before it stays valid.
+    ///
+    /// # Safety
+    /// Nothing allocated after the checkpoint may be used again, including vectors from before it that grew since.
+    /// Once the arena has been rolled back to a checkpoint, those made after it can't be rolled back to anymore.
+    /// The checkpoint's borrow already rules out using it after `reset`.
+    pub unsafe fn rollback(&self, checkpoint: Checkpoint<'_>) {
+        assert!(ptr::eq(checkpoint.arena, self as *const Self as *const ()), "Checkpoint is from a different arena");
+        assert!(checkpoint.chunk < self.chunks.borrow().len(), "Checkpoint was made after one that was rolled back to");
+        let freed = self.chunks.borrow_mut().split_off(checkpoint.chunk + 1);
+        freed.iter().for_each(|chunk| unsafe { dealloc(chunk.memory, chunk.layout) });
+
+        let freed: usize = freed.iter().map(|chunk| chunk.layout.size()).sum();
+        self.allocated.set(self.allocated.get() - freed);
+        self.rewind_to(checkpoint.n, checkpoint.top);
+        self.record(|stats| {
+            stats.used = checkpoint.used;
+            stats.padding = checkpoint.padding;
+        });
+    }
+
+    /// makes the last chunk the current one again, with `n` bytes of it in use
+    fn rewind_to(&self, n: usize, top: usize) {
+        let chunks = self.chunks.borrow();
+        let chunk = chunks.last().unwrap();
+        self.memory.set(chunk.memory);
+        self.capacity.set(chunk.layout.size());
+        self.n.set(n);
+        self.top.set(top);
     }
 
     pub fn dump(&self) {
-        println!("{:?}", unsafe { from_raw_parts(self.memory, self.layout.size()) });
+        self.chunks.borrow().iter().for_each(|chunk| {
+            println!("{:?}", unsafe { from_raw_parts(chunk.memory, chunk.layout.size()) });
+        });
     }
 }
 
 impl Drop for Arena<'_> {
     fn drop(&mut self) {
-        unsafe { dealloc(self.memory, self.layout ); }
+        self.chunks.get_mut().iter().for_each(|chunk| unsafe { dealloc(chunk.memory, chunk.layout) });
     }
 }
 
+// `NonNull` rather than `*mut` keeps these covariant like `Vec` and `Box`, so that a parsed template can be
+// rendered into an arena that doesn't live as long as the one it was parsed into
 pub struct ArenaVec<'a, T> {
-    mem: *mut T,
+    mem: NonNull<T>,
     len: usize,
     cap: usize,
     arena: &'a Arena<'a> 
 }
 
 impl<'a, T> ArenaVec<'a, T> {
-    pub fn new(arena: &'a Arena) -> Self {
-        let ptr = unsafe { arena.alloc_bytes(size_of::<T>()) };
-
+    /// doesn't allocate until the first element is pushed
+    pub fn new(arena: &'a Arena<'a>) -> Self {
+        assert_no_drop::<T>();
         ArenaVec {
-            mem: ptr as *mut T,
+            mem: NonNull::dangling(),
             len: 0,
-            cap: 1,
+            // zero sized elements never need any room
+            cap: if size_of::<T>() == 0 { usize::MAX } else { 0 },
             arena,
         }
     }
 
-    pub fn push(&mut self, item: T) {
-        if self.len + 1 > self.cap {
-            unsafe { 
-                self.mem = self.arena.realloc(self.mem as _, self.cap, self.cap * 2) ;
-            }
-            self.cap *= 2;
+    pub fn try_with_capacity(arena: &'a Arena<'a>, capacity: usize) -> Result<Self, OutOfMemory> {
+        let mut vec = Self::new(arena);
+        vec.try_reserve(capacity)?;
+        Ok(vec)
+    }
+
+    pub fn with_capacity(arena: &'a Arena<'a>, capacity: usize) -> Self {
+        Self::try_with_capacity(arena, capacity).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// makes room for at least `additional` more elements
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), OutOfMemory> {
+        let needed = self.len.checked_add(additional).expect("ArenaVec capacity overflow");
+        if needed <= self.cap {
+            return Ok(());
         }
 
-        unsafe { ptr::write(self.mem.add(self.len), item) };
+        let cap = needed.max(self.cap * 2).max(4);
+        unsafe {
+            self.mem = NonNull::new_unchecked(self.arena.try_realloc(self.mem.as_ptr(), self.cap, cap)?);
+        }
+        self.cap = cap;
+        Ok(())
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.try_reserve(additional).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_push(&mut self, item: T) -> Result<(), OutOfMemory> {
+        self.try_reserve(1)?;
+        unsafe { ptr::write(self.mem.as_ptr().add(self.len), item) };
         self.len += 1;
+        Ok(())
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.try_push(item).unwrap_or_else(|err| panic!("{err}"))
     }
 
     pub fn pop(&mut self) -> Option<T> {
@@ -152,8 +411,25 @@ impl<'a, T> ArenaVec<'a, T> {
             None
         } else {
             self.len -= 1;
-            unsafe { Some(ptr::read(self.mem.add(self.len))) }
+            unsafe { Some(ptr::read(self.mem.as_ptr().add(self.len))) }
+        }
+    }
+
+    /// inserts `item` at `index`, shifting everything after it to the right
+    pub fn insert(&mut self, index: usize, item: T) {
+        assert!(index <= self.len, "insertion index (is {index}) should be <= len (is {})", self.len);
+        self.reserve(1);
+        unsafe {
+            let ptr = self.mem.as_ptr().add(index);
+            ptr::copy(ptr, ptr.add(1), self.len - index);
+            ptr::write(ptr, item);
         }
+        self.len += 1;
+    }
+
+    /// removes every element, keeping the memory for reuse. Nothing in the arena needs dropping
+    pub fn clear(&mut self) {
+        self.len = 0;
     }
 
     #[inline]
@@ -161,16 +437,62 @@ impl<'a, T> ArenaVec<'a, T> {
         self.len
     }
 
-    pub fn iter(&self) -> Iter<'a, T> {
-        Iter {
-            start: self.mem,
-            end: unsafe { self.mem.add(self.len()) },
-            _iter: PhantomData,
-        }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cap
     }
 
     pub fn into_slice(self) -> &'a [T] {
-        unsafe { from_raw_parts(self.mem, self.len) }
+        unsafe { from_raw_parts(self.mem.as_ptr(), self.len) }
+    }
+}
+
+impl<'a, T: Clone> Clone for ArenaVec<'a, T> {
+    fn clone(&self) -> Self {
+        let mut vec = ArenaVec::with_capacity(self.arena, self.len);
+        vec.extend(self.iter().cloned());
+        vec
+    }
+}
+
+impl<'a, T> Extend<T> for ArenaVec<'a, T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        iter.for_each(|item| self.push(item));
+    }
+}
+
+impl<'a, T> Deref for ArenaVec<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { from_raw_parts(self.mem.as_ptr(), self.len) }
+    }
+}
+
+impl<'a, T> DerefMut for ArenaVec<'a, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { from_raw_parts_mut(self.mem.as_ptr(), self.len) }
+    }
+}
+
+impl<'a, T, I: SliceIndex<[T]>> Index<I> for ArenaVec<'a, T> {
+    type Output = I::Output;
+
+    fn index(&self, index: I) -> &I::Output {
+        &self.deref()[index]
+    }
+}
+
+impl<'a, T, I: SliceIndex<[T]>> IndexMut<I> for ArenaVec<'a, T> {
+    fn index_mut(&mut self, index: I) -> &mut I::Output {
+        &mut self.deref_mut()[index]
     }
 }
 
@@ -181,33 +503,49 @@ impl<'a, T: Debug> Debug for ArenaVec<'a, T> {
 }
 
 impl<'a, T: 'a> AsRef<[T]> for ArenaVec<'a, T> {
-    fn as_ref(&self) -> &'a [T] {
-        unsafe { from_raw_parts(self.mem, self.len) }
+    fn as_ref(&self) -> &[T] {
+        self
     }
 }
 
-pub struct Iter<'a, T> {
-    start: *const T,
-    end: *const T,
-    _iter: PhantomData<&'a T>
+impl<'v, 'a, T> IntoIterator for &'v ArenaVec<'a, T> {
+    type Item = &'v T;
+    type IntoIter = slice::Iter<'v, T>;
+
+    fn into_iter(self) -> slice::Iter<'v, T> {
+        self.iter()
+    }
 }
 
-impl<'a, T> Iterator for Iter<'a, T> {
-    type Item = &'a T;
+impl<'a, T: 'a> IntoIterator for ArenaVec<'a, T> {
+    type Item = T;
+    type IntoIter = IntoIter<'a, T>;
+
+    fn into_iter(self) -> IntoIter<'a, T> {
+        IntoIter { iter: self.into_slice().iter() }
+    }
+}
+
+/// moves the elements out of an `ArenaVec`, leaving its memory to the arena
+pub struct IntoIter<'a, T> {
+    iter: slice::Iter<'a, T>,
+}
+
+impl<'a, T> Iterator for IntoIter<'a, T> {
+    type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.start >= self.end {
-            return None;
-        }
+        // the vector was consumed, so each element is only read out once
+        self.iter.next().map(|item| unsafe { ptr::read(item) })
+    }
 
-        let item = unsafe { &*self.start };
-        self.start = unsafe { self.start.add(1) };
-        Some(item)
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
     }
 }
 
 pub struct ArenaBox<'a, T> {
-    mem: *mut T,
+    mem: NonNull<T>,
     _arena: PhantomData<&'a T>,
 }
 
@@ -215,14 +553,14 @@ impl<'a, T> ArenaBox<'a, T> {
     pub fn new(arena: &'a Arena, thing: T) -> Self {
         let mem = arena.alloc(thing);
         ArenaBox {
-            mem,
+            mem: NonNull::from(mem),
             _arena: PhantomData,
         }
     }
 
     #[inline]
     pub fn into_inner(self) -> T {
-        unsafe {ptr::read(self.mem)}
+        unsafe {ptr::read(self.mem.as_ptr())}
     }
 }
 
@@ -230,13 +568,13 @@ impl<'a, T> Deref for ArenaBox<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        unsafe {&*self.mem}
+        unsafe {self.mem.as_ref()}
     }
 }
 
 impl<'a, T> DerefMut for ArenaBox<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe {&mut *self.mem}
+        unsafe {self.mem.as_mut()}
     }
 }
 
@@ -244,4 +582,166 @@ impl<'a, T: Debug> Debug for ArenaBox<'a, T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.deref().fmt(f)
     }
-}
\ No newline at end of file
+}
+// the arena is all unsafe code, so these are worth running under Miri too: `cargo +nightly miri test arena`
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_past_the_first_chunk() {
+        let arena = Arena::new(8);
+        let first = arena.alloc_str("hello");
+        let strings: Vec<_> = (0..100).map(|i| &*arena.alloc_str(&format!("string {i}"))).collect();
+        let mut vec = ArenaVec::new(&arena);
+        (0..1000).for_each(|i| vec.push(i));
+
+        assert_eq!(first, "hello");
+        assert!(strings.iter().enumerate().all(|(i, string)| *string == format!("string {i}")));
+        assert!(vec.iter().copied().eq(0..1000));
+        assert!(arena.allocated() > 8);
+    }
+
+    #[test]
+    fn enforces_its_limit() {
+        let arena = Arena::new(16).with_limit(64);
+        assert!(arena.try_alloc_str(&"a".repeat(40)).is_ok());
+        assert_eq!(arena.try_alloc_str(&"b".repeat(40)), Err(OutOfMemory { requested: 40, limit: 64 }));
+        assert_eq!(arena.try_alloc_str("the rest").map(|str| &*str), Ok("the rest"));
+
+        let mut vec = ArenaVec::new(&arena);
+        assert!((0..64u64).try_for_each(|n| vec.try_push(n)).is_err());
+        assert!(vec.len() < 64);
+        assert!(arena.allocated() <= 64);
+    }
+
+    fn is_aligned<T>(ptr: *const T) -> bool {
+        ptr.is_aligned()
+    }
+
+    #[test]
+    fn aligns_every_allocation() {
+        #[repr(align(32))]
+        struct Wide(u8);
+
+        let arena = Arena::new(64);
+        for i in 0..64 {
+            arena.alloc_str(&"x".repeat(i % 7));
+            assert!(is_aligned(arena.alloc(i as u64)));
+            arena.alloc(i as u8);
+            assert!(is_aligned(arena.alloc(i as u128)));
+            assert!(is_aligned(arena.alloc(Wide(i as u8))));
+            assert!(is_aligned(arena.alloc_slice(&[i as u32, 1, 2]).as_ptr()));
+            assert!(is_aligned(&*ArenaBox::new(&arena, i as u64)));
+        }
+    }
+
+    #[test]
+    fn aligns_vectors_as_they_grow() {
+        let arena = Arena::new(16);
+        let mut bytes = ArenaVec::new(&arena);
+        let mut words = ArenaVec::new(&arena);
+        for i in 0..100u64 {
+            bytes.push(i as u8);
+            words.push(i);
+            assert!(is_aligned(words.as_ref().as_ptr()));
+        }
+
+        assert!(bytes.iter().copied().eq(0..100));
+        assert!(words.iter().copied().eq(0..100));
+    }
+
+    #[test]
+    fn reuses_its_memory_after_a_reset() {
+        let mut arena = Arena::new(16).with_limit(256);
+        for round in 0..100 {
+            let strings: Vec<_> = (0..10).map(|i| &*arena.alloc_str(&format!("{round}:{i}"))).collect();
+            assert_eq!(strings[9], format!("{round}:9"));
+            arena.reset();
+        }
+        assert!(arena.allocated() <= 256);
+    }
+
+    #[test]
+    fn rolls_back_to_a_checkpoint() {
+        let arena = Arena::new(16);
+        let kept = arena.alloc_str("kept");
+        let checkpoint = arena.checkpoint();
+        let allocated = arena.allocated();
+
+        for i in 0..100u64 {
+            arena.alloc(i);
+        }
+        unsafe { arena.rollback(checkpoint) };
+        assert_eq!(arena.allocated(), allocated);
+
+        let next = arena.alloc_str("next");
+        assert_eq!(kept, "kept");
+        assert_eq!(next, "next");
+        assert_eq!(kept.as_ptr().wrapping_add(kept.len()), next.as_ptr());
+    }
+
+    #[test]
+    fn keeps_statistics() {
+        let mut arena = Arena::new(16);
+        arena.alloc(1u8);
+        arena.alloc(2u64);
+        let mut vec = ArenaVec::new(&arena);
+        vec.extend([1u32, 2, 3, 4]);
+        arena.alloc(3u8);
+        vec.push(5);
+
+        let stats = arena.stats();
+        assert_eq!(stats.used, 1 + 8 + 4 * 4 + 1 + 8 * 4);
+        assert_eq!(stats.padding, 7);
+        assert_eq!(stats.copies, 1);
+        assert_eq!(stats.chunks, 3);
+        assert_eq!(stats.allocated, arena.allocated());
+
+        arena.reset();
+        let stats = arena.stats();
+        assert_eq!((stats.used, stats.padding, stats.peak, stats.chunks), (0, 0, 58, 1));
+    }
+
+    #[test]
+    fn only_allocates_vectors_when_needed() {
+        let arena = Arena::new(64);
+        let empty: ArenaVec<u64> = ArenaVec::new(&arena);
+        assert_eq!((empty.len(), empty.capacity()), (0, 0));
+        assert_eq!(arena.stats().used, 0);
+
+        let mut vec = ArenaVec::with_capacity(&arena, 10);
+        vec.extend(0..10u64);
+        assert_eq!(vec.capacity(), 10);
+        assert_eq!(arena.stats().used, 80);
+        assert_eq!(arena.stats().copies, 0);
+    }
+
+    #[test]
+    fn works_like_a_vec() {
+        let arena = Arena::new(64);
+        let mut vec = ArenaVec::new(&arena);
+        vec.extend([1, 2, 4]);
+        vec.insert(2, 3);
+        vec.insert(0, 0);
+        vec.insert(5, 5);
+        assert_eq!(&vec[..], [0, 1, 2, 3, 4, 5]);
+        assert_eq!(vec[1..3], [1, 2]);
+
+        vec[0] = 10;
+        vec.sort();
+        assert_eq!(vec.last(), Some(&10));
+        assert_eq!((&vec).into_iter().sum::<i32>(), 25);
+
+        let copy = vec.clone();
+        vec.clear();
+        assert!(vec.is_empty());
+        vec.push(7);
+        assert_eq!(&vec[..], [7]);
+        assert!(copy.into_iter().eq([1, 2, 3, 4, 5, 10]));
+
+        let mut units = ArenaVec::new(&arena);
+        (0..3).for_each(|_| units.push(()));
+        assert_eq!(units.into_iter().count(), 3);
+    }
+}
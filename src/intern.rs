@@ -0,0 +1,96 @@
+use std::{cell::RefCell, collections::HashMap, fmt, hash::{Hash, Hasher}};
+
+use crate::arena::Arena;
+
+/// An interned identifier. Symbols are compared and hashed by their id alone, while the name is kept
+/// alongside for error messages and for identifiers that are used as strings, like object fields.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Symbol<'a> {
+    id: u32,
+    name: &'a str,
+}
+
+impl<'a> Symbol<'a> {
+    #[inline]
+    pub fn id(self) -> usize {
+        self.id as usize
+    }
+
+    #[inline]
+    pub fn name(self) -> &'a str {
+        self.name
+    }
+}
+
+impl PartialEq for Symbol<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol<'_> {}
+
+impl Hash for Symbol<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl fmt::Display for Symbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+/// bound by the renderer itself, so they get the same ids in every interner
+pub(crate) const LOOP: Symbol<'static> = Symbol { id: 0, name: "loop" };
+pub(crate) const SLOT: Symbol<'static> = Symbol { id: 1, name: "slot" };
+
+/// Hands out a symbol per distinct identifier, copying each name into the arena only the first time it's seen.
+pub(crate) struct Interner<'a> {
+    arena: &'a Arena<'a>,
+    symbols: RefCell<HashMap<&'a str, Symbol<'a>>>,
+}
+
+impl<'a> Interner<'a> {
+    pub fn new(arena: &'a Arena<'a>) -> Self {
+        let symbols = [LOOP, SLOT].into_iter().map(|symbol| (symbol.name, symbol)).collect();
+        Interner {
+            arena,
+            symbols: RefCell::new(symbols),
+        }
+    }
+
+    pub fn intern(&self, name: &str) -> Symbol<'a> {
+        if let Some(&symbol) = self.symbols.borrow().get(name) {
+            return symbol;
+        }
+
+        let mut symbols = self.symbols.borrow_mut();
+        let symbol = Symbol {
+            id: symbols.len().try_into().expect("Too many identifiers"),
+            name: self.arena.alloc_str(name),
+        };
+        symbols.insert(symbol.name, symbol);
+        symbol
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_each_name_once() {
+        let arena = Arena::new(64);
+        let interner = Interner::new(&arena);
+        let a = interner.intern("a");
+        let b = interner.intern("b");
+
+        assert_eq!(interner.intern("a"), a);
+        assert_ne!(a, b);
+        assert_eq!(interner.intern("a").name().as_ptr(), a.name().as_ptr());
+        assert_eq!(interner.intern("slot"), SLOT);
+        assert_eq!(arena.stats().used, 2);
+    }
+}
@@ -1,9 +1,9 @@
-use std::{cell::UnsafeCell, char, panic, str};
+use std::{cell::{Cell, UnsafeCell}, char, ops::Deref, panic, str};
 
-use crate::arena::{Arena, ArenaVec};
+use crate::{arena::Arena, intern::{Interner, Symbol}};
 
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Token<'a> {
+pub(crate) enum Token<'a, 's> {
     At,
     Hashtag,
     Colon,
@@ -11,12 +11,14 @@ pub(crate) enum Token<'a> {
     CParen,
     OBracket,
     CBracket,
+    OBrace,
+    CBrace,
     Plus,
     Minus,
     Slash,
     Asterisk,
     Percent,
-    #[allow(unused)] Dot,
+    Dot,
     Equals,
     NotEquals,
     GreaterThan,
@@ -28,29 +30,117 @@ pub(crate) enum Token<'a> {
     Bar,
     Comma,
     Concat,
+    Question,
+    Coalesce,
     If,
     Else,
     For,
     In,
-    Ident(&'a str),
+    Match,
+    Case,
+    Block,
+    Ident(Symbol<'a>),
     Boolean(bool),
     Number(f32),
-    String(&'a str),
+    String(LexedStr<'a, 's>),
     Keys,
     Base,
+    Delimiters,
+    Let,
+    With,
+    Include,
+    Import,
+    Macro,
+    Call,
 }
 
-pub type Template<'a> = &'a [Token<'a>];
+pub type Template<'a, 's> = &'a [Token<'a, 's>];
+
+/// The contents of a string literal. It borrows from the template, unless it had escapes that had to be
+/// unescaped into the arena.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum LexedStr<'a, 's> {
+    Source(&'s str),
+    Unescaped(&'a str),
+}
+
+impl Deref for LexedStr<'_, '_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match *self {
+            LexedStr::Source(str) => str,
+            LexedStr::Unescaped(str) => str,
+        }
+    }
+}
+
+impl<'a, 's: 'a> LexedStr<'a, 's> {
+    pub fn as_str(self) -> &'a str {
+        match self {
+            LexedStr::Source(str) => str,
+            LexedStr::Unescaped(str) => str,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum DocumentKind<'a, 's> {
     Markup(&'s str),
-    Template(Template<'a>),
+    Template(Template<'a, 's>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Delimiters {
+    pub open: String,
+    pub close: String,
+}
+
+impl Delimiters {
+    pub fn new(open: &str, close: &str) -> Self {
+        if open.is_empty() || close.is_empty() {
+            panic!("Delimiters cannot be empty");
+        }
+        if open.chars().any(char::is_whitespace) || close.chars().any(char::is_whitespace) {
+            panic!("Delimiters cannot contain whitespace");
+        }
+
+        Delimiters {
+            open: open.to_owned(),
+            close: close.to_owned(),
+        }
+    }
+
+    /// parses a pair of delimiters separated by whitespace, e.g. `"<% %>"`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut parts = spec.split_whitespace();
+        let (Some(open), Some(close), None) = (parts.next(), parts.next(), parts.next()) else {
+            return None;
+        };
+        Some(Delimiters::new(open, close))
+    }
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Delimiters::new("{", "}")
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LexerOptions {
+    pub delimiters: Delimiters,
+    /// removes lines that contain nothing but a block tag, e.g. `{#for ...}`, `{:else}` or `{/}`
+    pub trim_blocks: bool,
 }
 
 pub(crate) struct Lexer<'a, 's> {
     contents: UnsafeCell<&'s str>, // I'm sorry
     arena: &'a Arena<'a>,
+    interner: &'a Interner<'a>,
+    options: LexerOptions,
+    trim_next_markup: Cell<bool>, // set by a `~}`
+    brace_depth: Cell<usize>, // braces of object literals, which may look like the closing delimiter
 }
 
 enum Status {
@@ -58,14 +148,34 @@ enum Status {
     Eof,
 }
 
-impl<'a, 's> Lexer<'a, 's> {
-    pub fn new(contents: &'s str, arena: &'a Arena<'a>) -> Self {
+// strings are borrowed from the source into tokens in the arena, so the source has to outlive them
+impl<'a, 's: 'a> Lexer<'a, 's> {
+    pub fn new(contents: &'s str, arena: &'a Arena<'a>, interner: &'a Interner<'a>) -> Self {
         Lexer {
             contents: UnsafeCell::new(contents),
             arena,
+            interner,
+            options: LexerOptions::default(),
+            trim_next_markup: Cell::new(false),
+            brace_depth: Cell::new(0),
         }
     }
 
+    pub fn with_options(mut self, options: LexerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn with_delimiters(mut self, delimiters: Delimiters) -> Self {
+        self.options.delimiters = delimiters;
+        self
+    }
+
+    fn starts_with(&self, pattern: &str) -> bool {
+        unsafe { *self.contents.get() }.starts_with(pattern)
+    }
+
     fn next_char(&self) -> Option<char> {
         let str = unsafe { *self.contents.get() };
         let next = str.chars().next();
@@ -83,6 +193,11 @@ impl<'a, 's> Lexer<'a, 's> {
         *str = &str[n..];
     }
 
+    fn nth_str(&self, n: usize) -> &'s str {
+        let str = unsafe { *self.contents.get() };
+        str.get(n..).unwrap_or("")
+    }
+
     fn peek_char(&self) -> Option<char> {
         let str = unsafe { *self.contents.get() };
         str.chars().next()
@@ -91,8 +206,7 @@ impl<'a, 's> Lexer<'a, 's> {
     fn nth(&self, n: usize) -> Option<char> {
         unsafe { *self.contents.get() }
             .as_bytes()
-            .iter()
-            .nth(n)
+            .get(n)
             .map(|&b| b as char) 
     }
 
@@ -105,25 +219,20 @@ impl<'a, 's> Lexer<'a, 's> {
         }
     }
 
-    fn read_until(&self, target: char) -> (&'s str, Status) {
-        let str = unsafe { &mut *self.contents.get() };
-        let mut n = 0;
-        while let Some(char) = self.nth(n) {
-            if char == target {
-                let res = (&str[0..n], Status::Continue);
-                self.advance_n(n + 1); // +1 to skip the target character
-                return res;
-            }
-            n += 1;
+    fn read_until(&self, target: &str) -> (&'s str, Status) {
+        let str = unsafe { *self.contents.get() };
+        if let Some(n) = str.find(target) {
+            let res = (&str[0..n], Status::Continue);
+            self.advance_n(n + target.len()); // skip the target as well
+            return res;
         }
 
-        let res = (&str[0..n], Status::Eof);
-        self.advance_n(n);
-        res
+        self.advance_n(str.len());
+        (str, Status::Eof)
     }
 
     fn read_while(&self, predicate: impl Fn(char) -> bool) -> &str {
-        let str = unsafe { &mut *self.contents.get() };
+        let str = unsafe { *self.contents.get() };
         let mut n = 0;
         while let Some(char) = self.nth(n) {
             if !predicate(char) {
@@ -137,7 +246,9 @@ impl<'a, 's> Lexer<'a, 's> {
         res
     }
 
-    fn next_ident(&self) -> Token<'a> {
+    /// `previous` holds the tokens read so far in this template. Keywords added after the first release are only
+    /// keywords where a directive expects them, so that older templates can keep using them as variable names.
+    fn next_ident(&self, previous: &[Token<'a, 's>]) -> Token<'a, 's> {
         let string = self.read_while(|char| char.is_alphanumeric() || char == '_');
 
         let token = match string {
@@ -149,13 +260,33 @@ impl<'a, 's> Lexer<'a, 's> {
             "base" => Token::Base,
             "true" => Token::Boolean(true),
             "false" => Token::Boolean(false),
-            _ => Token::Ident(self.arena.alloc_str(string)),
+            _ if !Self::is_directive_position(previous, string) => Token::Ident(self.interner.intern(string)),
+            "match" => Token::Match,
+            "case" => Token::Case,
+            "block" => Token::Block,
+            "let" => Token::Let,
+            "with" => Token::With,
+            "include" => Token::Include,
+            "import" => Token::Import,
+            "macro" => Token::Macro,
+            "call" => Token::Call,
+            "delimiters" => Token::Delimiters,
+            _ => Token::Ident(self.interner.intern(string)),
         };
         token
     }
 
-    fn next_number(&self) -> Token<'a> {
-        if let Ok(number) = self.read_while(|char| char.is_numeric() || char == '.').parse() {
+    fn is_directive_position(previous: &[Token<'a, 's>], word: &str) -> bool {
+        match previous {
+            [Token::Hashtag | Token::At | Token::Colon] => true,
+            [Token::At, Token::Include, Token::String(_)] => word == "with",
+            _ => false,
+        }
+    }
+
+    fn next_number(&self) -> Token<'a, 's> {
+        // letters are read as well, so that something like `2s` is reported rather than lexed as two tokens
+        if let Ok(number) = self.read_while(|char| char.is_alphanumeric() || char == '.' || char == '_').parse() {
             return Token::Number(number);
         } else {
             panic!("Error reading number");
@@ -171,17 +302,25 @@ impl<'a, 's> Lexer<'a, 's> {
         }
     }
 
-    fn next_string(&self) -> Token<'a> {
+    fn next_string(&self) -> Token<'a, 's> {
         let open_quote = self.next_char();
         debug_assert_eq!(open_quote, Some('"'));
-        
-        let mut string = ArenaVec::new(self.arena);
+
+        // without any escapes, the string is exactly what's between the quotes
+        let str = unsafe { *self.contents.get() };
+        let end = str.find(['"', '\\']).unwrap_or(str.len());
+        if !str[end..].starts_with('\\') {
+            self.advance_n((end + 1).min(str.len())); // skip the closing quote as well
+            return Token::String(LexedStr::Source(&str[..end]));
+        }
+
+        let mut string = String::with_capacity(end * 2);
+        string.push_str(&str[..end]);
+        self.advance_n(end);
         let mut backslash_found = false;
-        let mut char_buf = [0; 4];
         while let Some(char) = self.next_char() {
             if backslash_found {
-                Self::unescape(char).encode_utf8(&mut char_buf).as_bytes().iter()
-                    .for_each(|b| string.push(*b));
+                string.push(Self::unescape(char));
                 backslash_found = false;
                 continue;
             }
@@ -191,34 +330,43 @@ impl<'a, 's> Lexer<'a, 's> {
                 continue;
             }
 
-            char.encode_utf8(&mut char_buf).as_bytes().iter()
-                .for_each(|b| string.push(*b));
+            string.push(char);
         }
 
-        Token::String(unsafe {
-            str::from_utf8_unchecked(string.into_slice())
-        })
+        Token::String(LexedStr::Unescaped(self.arena.alloc_str(&string)))
     }
 
-    fn next_literal(&self) -> Token<'a> {
+    fn next_literal(&self, previous: &[Token<'a, 's>]) -> Token<'a, 's> {
         if let Some(peek) = self.peek_char() {
             if peek == '"' {
                 return self.next_string();
             } else if peek.is_numeric() {
                 return self.next_number();
             } else {
-                return self.next_ident();
+                return self.next_ident(previous);
             }
         }
         unreachable!()
     }
 
-    fn next_token(&self) -> Option<Token<'a>> {
+    fn next_token(&self, previous: &[Token<'a, 's>]) -> Option<Token<'a, 's>> {
         self.skip_whitespace();
 
+        // checked before anything else, as the closing delimiter may start with an operator, e.g. `%>`
+        let in_braces = self.brace_depth.get() > 0;
+        if !in_braces && self.starts_with(&self.options.delimiters.close) {
+            self.advance_n(self.options.delimiters.close.len());
+            return None;
+        }
+        if !in_braces && self.starts_with("~") && self.nth_str(1).starts_with(self.options.delimiters.close.as_str()) {
+            self.advance_n(1 + self.options.delimiters.close.len());
+            self.trim_next_markup.set(true);
+            return None;
+        }
+
         let first = self.peek_char()?;
         if matches!(first, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '"') {
-            return Some(self.next_literal());
+            return Some(self.next_literal(previous));
         }
         self.advance();
 
@@ -240,12 +388,18 @@ impl<'a, 's> Lexer<'a, 's> {
                 self.advance();
                 Some(Token::Concat)
             }
+            ('?', Some('?')) => {
+                self.advance();
+                Some(Token::Coalesce)
+            }
         
             ('@', _) => Some(Token::At),
             ('#', _) => Some(Token::Hashtag),
             (':', _) => Some(Token::Colon),
             ('/', _) => Some(Token::Slash),
             (',', _) => Some(Token::Comma),
+            ('.', _) => Some(Token::Dot),
+            ('?', _) => Some(Token::Question),
             ('+', _) => Some(Token::Plus),
             ('-', _) => Some(Token::Minus),
             ('*', _) => Some(Token::Asterisk),
@@ -260,7 +414,14 @@ impl<'a, 's> Lexer<'a, 's> {
             (')', _) => Some(Token::CParen),
             ('[', _) => Some(Token::OBracket),
             (']', _) => Some(Token::CBracket),
-            ('}', _) => None,
+            ('{', _) => {
+                self.brace_depth.set(self.brace_depth.get() + 1);
+                Some(Token::OBrace)
+            }
+            ('}', _) if in_braces => {
+                self.brace_depth.set(self.brace_depth.get() - 1);
+                Some(Token::CBrace)
+            }
 
             // having return here skips `self.current += 1` below the match stmt
             (first, _) => panic!("Unexpected character in template: {}", first),
@@ -269,35 +430,112 @@ impl<'a, 's> Lexer<'a, 's> {
         result
     }
 
-    fn next_template(&self) -> Template<'a> {
+    fn next_template(&self) -> Template<'a, 's> {
+        self.brace_depth.set(0);
         let mut template = Vec::new();
-        while let Some(token) = self.next_token() {
+        while let Some(token) = self.next_token(&template) {
             template.push(token);
         }
         self.arena.alloc_slice(template.as_ref())
     }
 
+    /// A file may start with `{@delimiters "<%" "%>"}` to switch the delimiters used for the rest of it.
+    fn read_delimiters_directive(&mut self) {
+        let contents = unsafe { *self.contents.get() };
+        let Some(rest) = contents.strip_prefix(self.options.delimiters.open.as_str()) else {
+            return;
+        };
+        if !rest.trim_start().starts_with("@delimiters") {
+            return;
+        }
+
+        self.advance_n(self.options.delimiters.open.len());
+        let delimiters = match self.next_template() {
+            [Token::At, Token::Delimiters, Token::String(open), Token::String(close)] => Delimiters::new(open, close),
+            _ => panic!("Malformed @delimiters directive. Example: `{{@delimiters \"<%\" \"%>\"}}`"),
+        };
+
+        // the directive takes up its own line, so don't leave an empty one behind
+        if self.starts_with("\r\n") {
+            self.advance_n(2);
+        } else if self.starts_with("\n") {
+            self.advance();
+        }
+        self.options.delimiters = delimiters;
+    }
+
     // pub fn execute(self: &'self mut Self<'a>) -> Vec<DocumentKind<'s>> {
     // 1. 'self |> return lives as long as &self lives
     // 2. 'a |> data in self lives as long as self lives 
     // 3. 'a: 'self
-    pub fn execute(self) -> Vec<DocumentKind<'a, 's>> {
+    pub fn execute(mut self) -> Vec<DocumentKind<'a, 's>> {
+        self.read_delimiters_directive();
+
         let mut result = Vec::new();
         loop {
-            match self.read_until('{') {
-                (before, Status::Continue) => result.push(DocumentKind::Markup(before)),
-                (before, Status::Eof) => {
-                    result.push(DocumentKind::Markup(before));
-                    break;
-                }
+            let (mut before, status) = self.read_until(&self.options.delimiters.open);
+            if self.trim_next_markup.replace(false) {
+                before = before.trim_start();
+            }
+            if self.starts_with("~") {
+                self.advance();
+                before = before.trim_end();
+            }
+            result.push(DocumentKind::Markup(before));
+            if let Status::Eof = status {
+                break;
             }
 
             let template = self.next_template();
             result.push(DocumentKind::Template(template));
         }
 
+        if self.options.trim_blocks {
+            Self::trim_block_lines(&mut result);
+        }
         result
     }
+
+    fn is_block_tag(template: Template<'a, 's>) -> bool {
+        matches!(template.first(), Some(Token::Hashtag | Token::Colon | Token::Slash | Token::At))
+    }
+
+    /// Removes the indentation before, and the line break after, every block tag that sits on a line of its own.
+    /// `result` always alternates between markup and templates, starting and ending with markup.
+    fn trim_block_lines(result: &mut [DocumentKind<'a, 's>]) {
+        // whether the markup before the current tag ended in a line break that the previous tag trimmed
+        let mut after_trimmed_line = false;
+        for i in (1..result.len()).step_by(2) {
+            let DocumentKind::Template(template) = result[i] else { unreachable!() };
+            let (DocumentKind::Markup(before), DocumentKind::Markup(after)) = (&result[i - 1], &result[i + 1]) else {
+                unreachable!()
+            };
+            let starts_line = i == 1 || after_trimmed_line || before.contains('\n');
+            after_trimmed_line = false;
+            if !Self::is_block_tag(template) || !starts_line {
+                continue;
+            }
+
+            // an empty segment between two tags on the same line is not the start of a line
+            let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+            if !before[line_start..].trim().is_empty() {
+                continue;
+            }
+            let line_end = match after.find('\n') {
+                Some(n) => n + 1,
+                None if i + 1 == result.len() - 1 => after.len(),
+                None => continue,
+            };
+            if !after[..line_end].trim().is_empty() {
+                continue;
+            }
+
+            let (before, after) = (&before[..line_start], &after[line_end..]);
+            after_trimmed_line = true;
+            result[i - 1] = DocumentKind::Markup(before);
+            result[i + 1] = DocumentKind::Markup(after);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -311,7 +549,8 @@ mod tests {
     fn categorizes_markup_and_templates() {
         let contents = "markup{}end";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), vec![
             DocumentKind::Markup("markup"),
             DocumentKind::Template(&[]),
@@ -323,7 +562,8 @@ mod tests {
     fn lexes_multiple_templates() {
         let contents = "markup 1: {}markup 2: {}markup 3: {}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), vec![
             DocumentKind::Markup("markup 1: "),
             DocumentKind::Template(&[]),
@@ -339,10 +579,11 @@ mod tests {
     fn skips_whitespace_and_recongnizes_idents() {
         let contents = "{      variable_1       }";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), vec![
             DocumentKind::Markup(""),
-            DocumentKind::Template(&[Token::Ident("variable_1")]),
+            DocumentKind::Template(&[Token::Ident(interner.intern("variable_1"))]),
             DocumentKind::Markup(""),
         ]);
     }
@@ -351,10 +592,11 @@ mod tests {
     fn recognizes_string() {
         let contents = r#"{"lorem ipsum"}"#;
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), vec![
             DocumentKind::Markup(""),
-            DocumentKind::Template(&[Token::String("lorem ipsum")]),
+            DocumentKind::Template(&[Token::String(LexedStr::Source("lorem ipsum"))]),
             DocumentKind::Markup(""),
         ]);
     }
@@ -363,20 +605,42 @@ mod tests {
     fn recognizes_escaped_string() {
         let contents = r#"{"\"lorem\\ipsum\"\n"}"#;
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), vec![
             DocumentKind::Markup(""),
-            DocumentKind::Template(&[Token::String("\"lorem\\ipsum\"\n")]),
+            DocumentKind::Template(&[Token::String(LexedStr::Unescaped("\"lorem\\ipsum\"\n"))]),
             DocumentKind::Markup(""),
         ]);
     }
 
+    #[test]
+    fn borrows_strings_without_escapes() {
+        let contents = r#"{"héllo" ++ "wörld\t!"}"#;
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
+        let used = arena.stats().used;
+        let result = lexer.execute();
+        let DocumentKind::Template([Token::String(borrowed), Token::Concat, Token::String(unescaped)]) = result[1] else {
+            panic!("Expected two strings, found {:?}", result[1]);
+        };
+
+        assert_eq!(*borrowed, LexedStr::Source("héllo"));
+        assert_eq!(borrowed.as_ptr(), contents[2..].as_ptr());
+        assert_eq!(*unescaped, LexedStr::Unescaped("wörld\t!"));
+        // the escaped string is the only one copied into the arena
+        let tokens = size_of::<Token>() * 3 + align_of::<Token>();
+        assert!(arena.stats().used - used <= tokens + "wörld\t!".len());
+    }
+
     #[test]
     #[should_panic]
     fn panics_on_deformed_escape_char() {
         let contents = r#"{\q}"#;
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         lexer.execute();
     }
 
@@ -384,7 +648,8 @@ mod tests {
     fn recognizes_number() {
         let contents = "{23491.23}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), &[
             DocumentKind::Markup(""),
             DocumentKind::Template(&[Token::Number(23491.23)]),
@@ -397,7 +662,8 @@ mod tests {
     fn panics_on_deformed_number() {
         let contents = "{2s3491.23}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         lexer.execute();
     }
 
@@ -405,7 +671,8 @@ mod tests {
     fn recognizes_boolean() {
         let contents = "{true} {false}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), &[
             DocumentKind::Markup(""),
             DocumentKind::Template(&[Token::Boolean(true)]),
@@ -419,7 +686,8 @@ mod tests {
     fn recognizes_keywords() {
         let contents = "{if else for in keys}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), &[
             DocumentKind::Markup(""),
             DocumentKind::Template(&[
@@ -433,11 +701,37 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn recognizes_directive_keywords_only_in_directives() {
+        let contents = "{#block call}{@include \"a\" with let=block}{block}";
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup(""),
+            DocumentKind::Template(&[Token::Hashtag, Token::Block, Token::Ident(interner.intern("call"))]),
+            DocumentKind::Markup(""),
+            DocumentKind::Template(&[
+                Token::At,
+                Token::Include,
+                Token::String(LexedStr::Source("a")),
+                Token::With,
+                Token::Ident(interner.intern("let")),
+                Token::Equals,
+                Token::Ident(interner.intern("block")),
+            ]),
+            DocumentKind::Markup(""),
+            DocumentKind::Template(&[Token::Ident(interner.intern("block"))]),
+            DocumentKind::Markup(""),
+        ]);
+    }
+
     #[test]
     fn recognizes_tokens() {
         let contents = "{#:/@}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), &[
             DocumentKind::Markup(""),
             DocumentKind::Template(&[
@@ -452,9 +746,10 @@ mod tests {
 
     #[test]
     fn recognizes_two_length_tokens() {
-        let contents = "{<= >= != ++}";
+        let contents = "{<= >= != ++ ?? ?}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), &[
             DocumentKind::Markup(""),
             DocumentKind::Template(&[
@@ -462,6 +757,8 @@ mod tests {
                 Token::GreaterThanOrEquals,
                 Token::NotEquals,
                 Token::Concat,
+                Token::Coalesce,
+                Token::Question,
             ]),
             DocumentKind::Markup(""),
         ]);
@@ -471,15 +768,16 @@ mod tests {
     fn bunch_of_stuff() {
         let contents = "{#if len(list) > 4 & true}and {\"yes \" ++ \"it works\"}.{:else}no{/}";
         let arena = arena::Arena::new(ARENA_SIZE);
-        let lexer = Lexer::new(&contents, &arena);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
         assert_eq!(lexer.execute(), &[
             DocumentKind::Markup(""),
             DocumentKind::Template(&[
                 Token::Hashtag,
                 Token::If,
-                Token::Ident("len"),
+                Token::Ident(interner.intern("len")),
                 Token::OParen,
-                Token::Ident("list"),
+                Token::Ident(interner.intern("list")),
                 Token::CParen,
                 Token::GreaterThan,
                 Token::Number(4.0),
@@ -488,9 +786,9 @@ mod tests {
             ]),
             DocumentKind::Markup("and "),
             DocumentKind::Template(&[
-                Token::String("yes "),
+                Token::String(LexedStr::Source("yes ")),
                 Token::Concat,
-                Token::String("it works"),
+                Token::String(LexedStr::Source("it works")),
             ]),
             DocumentKind::Markup("."),
             DocumentKind::Template(&[
@@ -504,4 +802,104 @@ mod tests {
             DocumentKind::Markup(""),
         ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn lexes_multi_character_delimiters() {
+        let contents = "{ {{name}} }<%x % 2%>";
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner).with_delimiters(Delimiters::new("{{", "}}"));
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup("{ "),
+            DocumentKind::Template(&[Token::Ident(interner.intern("name"))]),
+            DocumentKind::Markup(" }<%x % 2%>"),
+        ]);
+
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner).with_delimiters(Delimiters::new("<%", "%>"));
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup("{ {{name}} }"),
+            DocumentKind::Template(&[Token::Ident(interner.intern("x")), Token::Percent, Token::Number(2.0)]),
+            DocumentKind::Markup(""),
+        ]);
+    }
+
+    #[test]
+    fn switches_delimiters_with_directive() {
+        let contents = "{@delimiters \"[[\" \"]]\"}\n{a}[[ b ]]";
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup("{a}"),
+            DocumentKind::Template(&[Token::Ident(interner.intern("b"))]),
+            DocumentKind::Markup(""),
+        ]);
+    }
+
+    #[test]
+    fn trim_markers_strip_adjacent_whitespace() {
+        let contents = "a  \n  {~#if x~}  \n b {/~}\n";
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup("a"),
+            DocumentKind::Template(&[Token::Hashtag, Token::If, Token::Ident(interner.intern("x"))]),
+            DocumentKind::Markup("b "),
+            DocumentKind::Template(&[Token::Slash]),
+            DocumentKind::Markup(""),
+        ]);
+    }
+
+    #[test]
+    fn trim_blocks_removes_lines_with_only_a_block_tag() {
+        let contents = "<ul>\n  {#for x in xs}\n  <li>{x}</li>\n  {/}\n</ul>";
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let options = LexerOptions { trim_blocks: true, ..Default::default() };
+        let lexer = Lexer::new(contents, &arena, &interner).with_options(options.clone());
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup("<ul>\n"),
+            DocumentKind::Template(&[Token::Hashtag, Token::For, Token::Ident(interner.intern("x")), Token::In, Token::Ident(interner.intern("xs"))]),
+            DocumentKind::Markup("  <li>"),
+            DocumentKind::Template(&[Token::Ident(interner.intern("x"))]),
+            DocumentKind::Markup("</li>\n"),
+            DocumentKind::Template(&[Token::Slash]),
+            DocumentKind::Markup("</ul>"),
+        ]);
+
+        let contents = "<ul>\n{#for x in xs}\n  <li>{x}{/}\n</ul>";
+        let lexer = Lexer::new(contents, &arena, &interner).with_options(options);
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup("<ul>\n"),
+            DocumentKind::Template(&[Token::Hashtag, Token::For, Token::Ident(interner.intern("x")), Token::In, Token::Ident(interner.intern("xs"))]),
+            DocumentKind::Markup("  <li>"),
+            DocumentKind::Template(&[Token::Ident(interner.intern("x"))]),
+            DocumentKind::Markup(""),
+            DocumentKind::Template(&[Token::Slash]),
+            DocumentKind::Markup("\n</ul>"),
+        ]);
+    }
+
+    #[test]
+    fn braces_inside_templates_do_not_close_them() {
+        let contents = "{ {a: {}} }";
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let lexer = Lexer::new(contents, &arena, &interner);
+        assert_eq!(lexer.execute(), &[
+            DocumentKind::Markup(""),
+            DocumentKind::Template(&[
+                Token::OBrace,
+                Token::Ident(interner.intern("a")),
+                Token::Colon,
+                Token::OBrace,
+                Token::CBrace,
+                Token::CBrace,
+            ]),
+            DocumentKind::Markup(""),
+        ]);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{cell::RefCell, collections::HashMap, fmt, fs::{self, read_to_string}, io, path::{Path, PathBuf}};
+
+use crate::{arena::Arena, intern::Interner, lexer::{Lexer, LexerOptions}, parser::{Content, Parser}};
+
+pub(crate) type Document<'a> = &'a [Content<'a, 'a>];
+
+/// how many templates deep a chain of `@base`s or includes may go
+pub(crate) const MAX_DEPTH: usize = 64;
+
+#[derive(Debug)]
+pub(crate) enum LoadError {
+    NotFound {
+        path: PathBuf,
+        searched: Vec<PathBuf>,
+    },
+    Io {
+        path: PathBuf,
+        err: io::Error,
+    },
+    /// `chain` ends with the template that was already part of it
+    Cycle {
+        chain: Vec<PathBuf>,
+    },
+    TooDeep {
+        chain: Vec<PathBuf>,
+    },
+}
+
+fn write_chain(f: &mut fmt::Formatter<'_>, chain: &[PathBuf]) -> fmt::Result {
+    chain.iter().enumerate().try_for_each(|(i, path)| {
+        let arrow = if i == 0 { "" } else { " -> " };
+        write!(f, "{arrow}{}", path.display())
+    })
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotFound { path, searched } => {
+                write!(f, "Could not find template {}. Looked for:", path.display())?;
+                searched.iter().try_for_each(|candidate| write!(f, "\n  {}", candidate.display()))
+            }
+            LoadError::Io { path, err } => write!(f, "Failed to read template {}: {err}", path.display()),
+            LoadError::Cycle { chain } => {
+                write!(f, "Template {} ends up using itself: ", chain[chain.len() - 1].display())?;
+                write_chain(f, chain)
+            }
+            LoadError::TooDeep { chain } => {
+                write!(f, "Templates are nested more than {MAX_DEPTH} levels deep: ")?;
+                write_chain(f, chain)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Checks that `path` can be used from the last template in `stack`, the templates it is nested in.
+pub(crate) fn check_nesting(stack: &[PathBuf], path: &Path) -> Result<(), LoadError> {
+    let chain = || stack.iter().cloned().chain(std::iter::once(path.to_owned())).collect();
+    if stack.iter().any(|visited| visited == path) {
+        Err(LoadError::Cycle { chain: chain() })
+    } else if stack.len() >= MAX_DEPTH {
+        Err(LoadError::TooDeep { chain: chain() })
+    } else {
+        Ok(())
+    }
+}
+
+/// Lexes and parses templates into the arena, so that they live as long as everything else in it.
+pub(crate) struct Loader<'a> {
+    arena: &'a Arena<'a>,
+    interner: &'a Interner<'a>,
+    options: LexerOptions,
+    template_dirs: Vec<PathBuf>,
+    cache: RefCell<HashMap<PathBuf, (Document<'a>, Option<PathBuf>)>>,
+}
+
+impl<'a> Loader<'a> {
+    pub fn new(arena: &'a Arena<'a>, interner: &'a Interner<'a>, options: LexerOptions) -> Self {
+        Loader {
+            arena,
+            interner,
+            options,
+            template_dirs: Vec::new(),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// directories to look in when a template isn't found next to the one referencing it
+    pub fn with_template_dirs(mut self, template_dirs: Vec<PathBuf>) -> Self {
+        self.template_dirs = template_dirs;
+        self
+    }
+
+    /// Finds the template that `path` refers to. Relative paths are looked up next to `from`, the template
+    /// they appear in (or the working directory if there isn't one), and then in each of the template directories.
+    pub fn resolve(&self, path: &Path, from: Option<&Path>) -> Result<PathBuf, LoadError> {
+        let searched: Vec<_> = if path.is_absolute() {
+            vec![path.to_owned()]
+        } else {
+            let dir = from.and_then(Path::parent).unwrap_or(Path::new(""));
+            std::iter::once(dir)
+                .chain(self.template_dirs.iter().map(PathBuf::as_path))
+                .map(|dir| dir.join(path))
+                .collect()
+        };
+
+        match searched.iter().find(|candidate| candidate.is_file()) {
+            // so that the same file is always cached under the same name
+            Some(found) => Ok(fs::canonicalize(found).unwrap_or_else(|_| found.clone())),
+            None => Err(LoadError::NotFound { path: path.to_owned(), searched }),
+        }
+    }
+
+    /// returns (the template parsed, the base template that this one extends from)
+    pub fn parse(&self, contents: &str) -> (Document<'a>, Option<PathBuf>) {
+        // use std::time::Instant;
+        // let before = Instant::now();
+        // println!("{:?}", Instant::now() - before);
+
+        let contents = self.arena.alloc_str(contents);
+        let lexer = Lexer::new(contents, self.arena, self.interner).with_options(self.options.clone());
+        let result = lexer.execute();
+
+        let parser = Parser::new(self.arena);
+        let (document, base_template) = parser.execute(result);
+        (document.into_slice(), base_template)
+    }
+
+    /// parses the template at `path`, reusing the result if it has been loaded before
+    pub fn load(&self, path: &Path) -> Result<(Document<'a>, Option<PathBuf>), LoadError> {
+        if let Some(loaded) = self.cache.borrow().get(path) {
+            return Ok(loaded.clone());
+        }
+
+        let contents = read_to_string(path).map_err(|err| LoadError::Io { path: path.to_owned(), err })?;
+        let loaded = self.parse(&contents);
+        self.cache.borrow_mut().insert(path.to_owned(), loaded.clone());
+        Ok(loaded)
+    }
+}
@@ -2,23 +2,29 @@ mod lexer;
 mod parser;
 mod arena;
 mod template;
+mod loader;
+mod intern;
 
-use lexer::Lexer;
-use parser::{Parser, Value};
-use std::{collections::HashMap, env, fs::read_to_string, io::{self, Read, stdin}, path::PathBuf, str::Chars};
-use template::{Environment, Augment};
+use lexer::{Delimiters, LexerOptions};
+use intern::{Interner, SLOT, Symbol};
+use loader::{Document, LoadError, Loader, check_nesting};
+use parser::Value;
+use std::{env, io::{self, Read, stdin}, path::PathBuf, process, str::Chars};
+use template::{Environment, render_chain};
 
-use crate::arena::Arena;
+use crate::arena::{Arena, ArenaVec};
 
 struct ArgumentParser<'a> {
     arena: &'a Arena<'a>,
+    interner: &'a Interner<'a>,
     // scratch: RefCell<String>,
 }
 
 impl<'a> ArgumentParser<'a> {
-    fn new(arena: &'a Arena<'a>) -> Self {
+    fn new(arena: &'a Arena<'a>, interner: &'a Interner<'a>) -> Self {
         Self {
             arena,
+            interner,
             // scratch: String::with_capacity(512).into(),
         }
     }
@@ -47,7 +53,7 @@ impl<'a> ArgumentParser<'a> {
     }
 
     fn parse_array(&mut self, inner: &mut Chars) -> Value<'a> {
-        let mut vec = Vec::new(); // recursive call, better not allocate it in arena
+        let mut vec = ArenaVec::new(self.arena);
         let mut scratch = String::new();
         while let Some(char) = inner.next() {
             if char == '[' {
@@ -75,17 +81,16 @@ impl<'a> ArgumentParser<'a> {
             scratch.push(char);
         }
 
-        let vec = self.arena.alloc_slice(&vec);
-        Value::Array(vec)
+        Value::Array(vec.into_slice())
     }
 
-    fn parse_argument(&mut self, param: String) -> (&'a str, Value<'a>) {
+    fn parse_argument(&mut self, param: String) -> (Symbol<'a>, Value<'a>) {
         let Some((ident, value)) = param.split_once('=') else {
             panic!("Expected equals sign in parameter specification. Example: username=\"John\"")
         };
         let value = value.trim();
         let value = self.parse_value(value);
-        (self.arena.alloc_str(ident), value)
+        (self.interner.intern(ident), value)
     }
 }
 
@@ -99,72 +104,116 @@ fn read_from_stdin() -> String {
     }
 }
 
-const ARENA_SIZE: usize = 16 * 1024;
-
-/// returns (the file templated, the base template that this one extends from)
-fn template_a_file<'a, 'env>(contents: String, arena: &'a Arena<'a>, env: &'env mut Environment<'a>) -> (String, Option<PathBuf>) {
-    // use std::time::Instant;
-    // let before = Instant::now();
-    // println!("{:?}", Instant::now() - before);
- 
-    let lexer = Lexer::new(&contents, &arena);
-    let result = lexer.execute();
-
-    let parser = Parser::new(&arena);
-    let (result, base_template) = parser.execute(result);
-
-    let iter = result.iter();
-    let templater = Augment::new(iter, env);
-    let result = templater.execute();
-
-    (result, base_template)
+/// parses the template and every @base it extends from, each base relative to the template naming it
+fn load_chain<'a>(
+    loader: &Loader<'a>,
+    path: Option<PathBuf>,
+) -> Result<Vec<(Document<'a>, Option<PathBuf>)>, LoadError> {
+    let (document, mut base_template, mut path) = match path {
+        Some(path) => {
+            let path = loader.resolve(&path, None)?;
+            let (document, base) = loader.load(&path)?;
+            (document, base, Some(path))
+        }
+        None => {
+            let (document, base) = loader.parse(&read_from_stdin());
+            (document, base, None)
+        }
+    };
+
+    let mut chain = vec![(document, path.clone())];
+    // templates read from stdin have no path, so they can't be part of a cycle
+    let mut visited: Vec<_> = path.iter().cloned().collect();
+    while let Some(base) = base_template {
+        let resolved = loader.resolve(&base, path.as_deref())?;
+        check_nesting(&visited, &resolved)?;
+        visited.push(resolved.clone());
+        let (document, base) = loader.load(&resolved)?;
+        chain.push((document, Some(resolved.clone())));
+        base_template = base;
+        path = Some(resolved);
+    }
+    Ok(chain)
 }
 
+const ARENA_SIZE: usize = 16 * 1024;
+
 fn main() -> io::Result<()> {
     let mut arguments = env::args().peekable();
     arguments.next();
 
-    let mut env = HashMap::new();
-    env.insert("slot", Value::String("".into()));
+    let mut env = Environment::new();
+    env.insert(SLOT, Value::String(""));
 
     let arena = arena::Arena::new(ARENA_SIZE);
+    let interner = Interner::new(&arena);
 
     // parse cmd line arguments
-    let mut advance = false;
-    let contents = arguments.peek().map(|argument| {
-        if argument.starts_with('-') {
-            return read_from_stdin();
-        }
-
-        advance = true;
-        match read_to_string(argument) {
-            Ok(contents) => return contents,
-            Err(err) => panic!("Failed to read file: {err}"),
-        }
-    }).unwrap_or_else(read_from_stdin);
-
-    if advance { arguments.next(); }
-    if let Some(argument) = arguments.next() {
-        let mut parser = ArgumentParser::new(&arena);
-        if argument == "-i" {
-            arguments.for_each(|arg| {
-                let (k, v) = parser.parse_argument(arg);
-                env.insert(k, v);
-            });
+    let path = arguments.next_if(|argument| !argument.starts_with('-')).map(PathBuf::from);
+    let mut options = LexerOptions::default();
+    let mut template_dirs = Vec::new();
+    let mut memory_limit = None;
+    let mut stats = false;
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "-i" => {
+                // the inputs run until the next flag, so -i can be followed by other options
+                let mut parser = ArgumentParser::new(&arena, &interner);
+                while let Some(arg) = arguments.next_if(|arg| !arg.starts_with('-')) {
+                    let (k, v) = parser.parse_argument(arg);
+                    env.insert(k, v);
+                }
+            }
+            "-d" | "--delimiters" => {
+                let Some(spec) = arguments.next() else {
+                    panic!("Expected delimiters after {argument}. Example: -d \"<% %>\"");
+                };
+                options.delimiters = Delimiters::parse(&spec).unwrap_or_else(|| {
+                    panic!("Expected an opening and closing delimiter separated by a space. Example: -d \"<% %>\"");
+                });
+            }
+            "--trim-blocks" => options.trim_blocks = true,
+            "--stats" => stats = true,
+            "--template-dir" => {
+                let Some(dir) = arguments.next() else {
+                    panic!("Expected a directory after {argument}. Example: --template-dir templates");
+                };
+                template_dirs.push(PathBuf::from(dir));
+            }
+            // only limits rendering, as templates and inputs go into `arena`, which exists before this is parsed
+            "--memory-limit" => {
+                let Some(limit) = arguments.next().and_then(|limit| limit.parse().ok()) else {
+                    panic!("Expected a number of bytes after {argument}. Example: --memory-limit 1048576");
+                };
+                memory_limit = Some(limit);
+            }
+            _ => panic!("Unrecognized argument: {argument}"),
         }
     }
 
-    let mut to_be_templated = contents;
-    loop {
-        let (result, base_template) = template_a_file(to_be_templated, &arena, &mut env);
-        if let Some(path) = base_template {
-            to_be_templated = read_to_string(path).unwrap();
-            env.insert("slot", Value::String(result.leak()));
-        } else {
-            println!("{result}");
-            break;
-        }
+    // the whole chain of templates is parsed before rendering any of it, so that blocks can be overridden
+    let loader = Loader::new(&arena, &interner, options).with_template_dirs(template_dirs);
+    let chain = load_chain(&loader, path).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1);
+    });
+
+    // the first chunk counts towards the limit as well
+    let mut render_arena = Arena::new(memory_limit.map_or(ARENA_SIZE, |limit: usize| limit.min(ARENA_SIZE)));
+    if let Some(limit) = memory_limit {
+        render_arena = render_arena.with_limit(limit);
+    }
+    let result = render_chain(&chain, &env, &loader, &mut render_arena);
+    // reported even when rendering fails, as that's when they're most useful
+    if stats {
+        eprintln!("templates: {}", arena.stats());
+        eprintln!("rendering: {}", render_arena.stats());
     }
+    let result = result.unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1);
+    });
+    println!("{result}");
 
     Ok(())
 }
\ No newline at end of file
@@ -1,12 +1,16 @@
 use crate::lexer::{DocumentKind, Template, Token};
 use crate::arena::{Arena, ArenaBox, ArenaVec};
+use crate::intern::Symbol;
 use std::{mem, path::PathBuf, fmt::Write};
 
 #[derive(Debug)]
 pub(crate) enum Content<'a, 's> {
     Markup(&'s str),
     Expression(ExprRef<'a>),
-    Keys(ArenaVec<'a, &'a str>),
+    Keys(ArenaVec<'a, Symbol<'a>>),
+    Let { ident: Symbol<'a>, value: ExprRef<'a> },
+    Include { path: &'a str, bindings: ArenaVec<'a, (Symbol<'a>, ExprRef<'a>)> },
+    Import { path: &'a str },
     Block { kind: Block<'a> },
     EndBlock,
 }
@@ -21,10 +25,41 @@ pub(crate) enum Block<'a> {
     },
     Else,
     For {
-        //  to be an identifier
-        element: Value<'a>,
-        iterable: Value<'a>,
+        /// bound to the index of an array, or the key of an object, in `{#for key, value in iterable}`
+        key: Option<Binding<'a>>,
+        element: Binding<'a>,
+        iterable: ExprRef<'a>,
     },
+    With {
+        bindings: ArenaVec<'a, (Symbol<'a>, ExprRef<'a>)>,
+    },
+    Match {
+        subject: ExprRef<'a>,
+    },
+    Case {
+        values: ArenaVec<'a, ExprRef<'a>>,
+    },
+    /// a section that templates extending this one may override
+    Named {
+        name: &'a str,
+    },
+    Macro {
+        name: Symbol<'a>,
+        /// each parameter, along with its default value
+        params: ArenaVec<'a, (Symbol<'a>, Option<ExprRef<'a>>)>,
+    },
+    /// calls a macro, which can render the body of this block with `caller()`
+    Call {
+        call: ExprRef<'a>,
+    },
+}
+
+/// The variables that the elements of a for loop are bound to
+#[derive(Debug)]
+pub(crate) enum Binding<'a> {
+    Ident(Symbol<'a>),
+    /// `[id, name]`, which binds each element of an array to its own variable
+    Array(ArenaVec<'a, Binding<'a>>),
 }
 
 pub type ExprRef<'a> = ArenaBox<'a, Expr<'a>>;
@@ -41,19 +76,27 @@ pub(crate) enum Expr<'a> {
         value: ExprRef<'a>,
     },
     Function {
-        ident: &'a str,
+        ident: Symbol<'a>,
         arguments: ArenaVec<'a, ExprRef<'a>>,
     },
+    Array(ArenaVec<'a, ExprRef<'a>>),
+    Object(ArenaVec<'a, (&'a str, ExprRef<'a>)>),
+    Conditional {
+        condition: ExprRef<'a>,
+        then: ExprRef<'a>,
+        otherwise: ExprRef<'a>,
+    },
     Value(Value<'a>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Value<'a> {
     Boolean(bool),
     Number(f32),
     String(&'a str),
-    VarRef(&'a str),
-    Array(&'a [Value<'a>]), // this is only possible via the environment
+    VarRef(Symbol<'a>),
+    Array(&'a [Value<'a>]), // from the environment, or an evaluated array literal
+    Object(&'a [(&'a str, Value<'a>)]), // kept in insertion order
     Null,
 }
 
@@ -96,6 +139,7 @@ impl<'a> Value<'a> {
             Value::Null => "null".to_owned(),
             Value::VarRef(_) => panic!(),
             Value::Array(_) => panic!("Cannot convert array to string"),
+            Value::Object(_) => panic!("Cannot convert object to string"),
         }
     }
 
@@ -103,10 +147,11 @@ impl<'a> Value<'a> {
         match self {
             Value::Boolean(bool) => write!(buf, "{bool}").unwrap(),
             Value::Number(num) => write!(buf, "{num}").unwrap(),
-            Value::String(content) => buf.push_str(&content),
+            Value::String(content) => buf.push_str(content),
             Value::Null => buf.push_str("null"),
             Value::VarRef(_) => panic!(),
             Value::Array(_) => panic!("Cannot convert array to string"),
+            Value::Object(_) => panic!("Cannot convert object to string"),
         }
     }
 }
@@ -128,6 +173,7 @@ pub(crate) enum BinaryOp {
     Or,
     Concat,
     Index,
+    Coalesce,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -189,22 +235,39 @@ impl Operation for UnaryOp {
     }
 }
 
+/// the kind of each block that has been opened, but not yet closed with `{/}`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OpenBlock {
+    If,
+    For,
+    With,
+    Match,
+    Named,
+    Macro,
+    Call,
+}
+
 pub(crate) struct Parser<'a, 's> {
-    template: Template<'a>,
-    ast: Vec<Content<'a, 's>>,
+    template: Template<'a, 's>,
+    ast: ArenaVec<'a, Content<'a, 's>>,
+    open_blocks: Vec<OpenBlock>,
     current: usize,
     base_template: Option<PathBuf>,
+    /// how many `super()` and `caller()` calls the current template has, which render rather than evaluate to a value
+    render_calls: usize,
     arena: &'a Arena<'a>,
 }
 
-impl<'a, 's> Parser<'a, 's> {
+impl<'a, 's: 'a> Parser<'a, 's> {
     pub(crate) fn new(arena: &'a Arena) -> Self {
         Parser {
             template: &[],
-            ast: Vec::new(),
+            ast: ArenaVec::new(arena),
+            open_blocks: Vec::new(),
             current: 0,
             base_template: None,
-            arena: arena,
+            render_calls: 0,
+            arena,
         }
     }
 
@@ -228,57 +291,65 @@ impl<'a, 's> Parser<'a, 's> {
         Err(())
     }
 
-    fn peek(&self) -> Option<&Token<'_>> {
+    fn peek(&self) -> Option<&Token<'a, 's>> {
         self.template.get(self.current)
     }
 
-    fn next(&mut self) -> Option<Token<'a>> {
-        let current = self.template.get(self.current).map(|x| x.clone())?;
+    fn next(&mut self) -> Option<Token<'a, 's>> {
+        let current = self.template.get(self.current).cloned()?;
         self.current += 1;
         Some(current)
     }
 
-    fn parse_identifier(&mut self, ident: &'a str) -> ExprRef<'a> {
+    fn parse_identifier(&mut self, ident: Symbol<'a>) -> ExprRef<'a> {
         // function call
         if self.next_if(Token::OParen) {
             let mut arguments = ArenaVec::new(self.arena);
-            loop {
+            while !self.next_if(Token::CParen) {
                 let argument = self.parse_expression();
                 arguments.push(argument);
                 if self.next_if(Token::Comma) {
                     continue;
                 } else {
+                    self.expect(Token::CParen).expect("Missing closing paren");
                     break;
                 }
             }
-            self.expect(Token::CParen).expect("Missing closing paren");
 
-            return ArenaBox::new(self.arena, Expr::Function { ident, arguments });
+            if matches!(ident.name(), "super" | "caller") {
+                self.render_calls += 1;
+            }
+            return self.parse_indexing(Expr::Function { ident, arguments });
         }
 
-        if self.next_if(Token::OBracket) {
-            let index = self.parse_expression();
-            self.expect(Token::CBracket)
-                .expect("Missing closing bracket");
-            let mut indexing_onion = Expr::BinaryOp {
-                kind: BinaryOp::Index,
-                lhs: ArenaBox::new(self.arena, Expr::Value(Value::VarRef(ident))),
-                rhs: index,
-            };
-            while self.next_if(Token::OBracket) {
+        self.parse_indexing(Expr::Value(Value::VarRef(ident)))
+    }
+
+    /// parses any `[index]` or `.field` following `indexed`
+    fn parse_indexing(&mut self, indexed: Expr<'a>) -> ExprRef<'a> {
+        let mut indexing_onion = indexed;
+        loop {
+            let index = if self.next_if(Token::OBracket) {
                 let index = self.parse_expression();
                 self.expect(Token::CBracket)
                     .expect("Missing closing bracket");
-                indexing_onion = Expr::BinaryOp {
-                    kind: BinaryOp::Index,
-                    lhs: ArenaBox::new(self.arena, indexing_onion),
-                    rhs: index,
+                index
+            } else if self.next_if(Token::Dot) {
+                let Some(Token::Ident(field)) = self.next() else {
+                    panic!("Expected field name after '.'");
                 };
-            }
-            return ArenaBox::new(self.arena, indexing_onion);
-        }
+                ArenaBox::new(self.arena, Expr::Value(Value::String(field.name())))
+            } else {
+                break;
+            };
 
-        return ArenaBox::new(self.arena, Expr::Value(Value::VarRef(ident)));
+            indexing_onion = Expr::BinaryOp {
+                kind: BinaryOp::Index,
+                lhs: ArenaBox::new(self.arena, indexing_onion),
+                rhs: index,
+            };
+        }
+        ArenaBox::new(self.arena, indexing_onion)
     }
 
     fn parse_factor(&mut self) -> ExprRef<'a> {
@@ -295,7 +366,7 @@ impl<'a, 's> Parser<'a, 's> {
             });
         }
         if self.next_if(Token::OParen) {
-            let inside = self.parse_logical();
+            let inside = self.parse_ternary();
             self.expect(Token::CParen).expect("Expected '('");
             return ArenaBox::new(self.arena, Expr::UnaryOp {
                 kind: UnaryOp::Dummy,
@@ -303,12 +374,41 @@ impl<'a, 's> Parser<'a, 's> {
             });
         }
 
+        if self.next_if(Token::OBracket) {
+            let mut elements = ArenaVec::new(self.arena);
+            while !self.next_if(Token::CBracket) {
+                elements.push(self.parse_ternary());
+                if !self.next_if(Token::Comma) {
+                    self.expect(Token::CBracket).expect("Missing closing bracket");
+                    break;
+                }
+            }
+            return self.parse_indexing(Expr::Array(elements));
+        }
+        if self.next_if(Token::OBrace) {
+            let mut entries = ArenaVec::new(self.arena);
+            while !self.next_if(Token::CBrace) {
+                let key = match self.next() {
+                    Some(Token::Ident(key)) => key.name(),
+                    Some(Token::String(key)) => key.as_str(),
+                    token => panic!("Expected object key, found {token:?}"),
+                };
+                self.expect(Token::Colon).expect("Expected ':' after object key");
+                entries.push((key, self.parse_ternary()));
+                if !self.next_if(Token::Comma) {
+                    self.expect(Token::CBrace).expect("Missing closing brace");
+                    break;
+                }
+            }
+            return self.parse_indexing(Expr::Object(entries));
+        }
+
         let val = match self.next() {
             Some(Token::Ident(ident)) => return self.parse_identifier(ident),
-            Some(Token::String(content)) => Value::String(content),
+            Some(Token::String(content)) => Value::String(content.as_str()),
             Some(Token::Boolean(bool)) => Value::Boolean(bool),
             Some(Token::Number(num)) => Value::Number(num),
-            Some(_) => unreachable!(),
+            Some(token) => panic!("Expected a value, found {token:?}"),
             None => panic!("Expected a value"),
         };
 
@@ -353,8 +453,8 @@ impl<'a, 's> Parser<'a, 's> {
         let rhs = self.parse_expression();
         ArenaBox::new(self.arena, Expr::BinaryOp {
             kind,
-            lhs: lhs,
-            rhs: rhs,
+            lhs,
+            rhs,
         })
     }
 
@@ -398,43 +498,183 @@ impl<'a, 's> Parser<'a, 's> {
         })
     }
 
+    fn parse_coalesce(&mut self) -> ExprRef<'a> {
+        let lhs = self.parse_logical();
+        if !self.next_if(Token::Coalesce) {
+            return lhs;
+        }
+
+        let rhs = self.parse_coalesce();
+        ArenaBox::new(self.arena, Expr::BinaryOp {
+            kind: BinaryOp::Coalesce,
+            lhs,
+            rhs,
+        })
+    }
+
+    fn parse_ternary(&mut self) -> ExprRef<'a> {
+        let condition = self.parse_coalesce();
+        if !self.next_if(Token::Question) {
+            return condition;
+        }
+
+        let then = self.parse_ternary();
+        self.expect(Token::Colon).expect("Expected ':' in conditional expression");
+        let otherwise = self.parse_ternary();
+        ArenaBox::new(self.arena, Expr::Conditional {
+            condition,
+            then,
+            otherwise,
+        })
+    }
+
     fn parse_block_declaration(&mut self) {
         let declaration = if self.next_if(Token::If) {
+            self.open_blocks.push(OpenBlock::If);
             Content::Block {
                 kind: Block::If {
-                    condition: self.parse_logical(),
+                    condition: self.parse_ternary(),
                 },
             }
         } else if self.next_if(Token::For) {
+            self.open_blocks.push(OpenBlock::For);
+            let mut key = None;
+            let mut element = self.parse_binding();
+            if self.next_if(Token::Comma) {
+                key = Some(element);
+                element = self.parse_binding();
+            }
             // NOTE: the self.expect function only compares the enum variant, and not the insides.
-            let Some(Token::Ident(element_ident)) = self.next() else {
-                panic!("Expected Identifier");
-            };
             self.expect(Token::In).expect("Expected in keyword");
-            let Some(Token::Ident(iterable_ident)) = self.next() else {
-                panic!("Expected Identifier");
-            };
 
             Content::Block {
                 kind: Block::For {
-                    element: Value::VarRef(element_ident),
-                    iterable: Value::VarRef(iterable_ident),
+                    key,
+                    element,
+                    iterable: self.parse_ternary(),
+                },
+            }
+        } else if self.next_if(Token::With) {
+            self.open_blocks.push(OpenBlock::With);
+            Content::Block {
+                kind: Block::With {
+                    bindings: self.parse_assignments(),
+                },
+            }
+        } else if self.next_if(Token::Match) {
+            self.open_blocks.push(OpenBlock::Match);
+            Content::Block {
+                kind: Block::Match {
+                    subject: self.parse_ternary(),
                 },
             }
+        } else if self.next_if(Token::Block) {
+            self.open_blocks.push(OpenBlock::Named);
+            let Some(Token::Ident(name)) = self.next() else {
+                panic!("Expected block name");
+            };
+            Content::Block {
+                kind: Block::Named { name: name.name() },
+            }
+        } else if self.next_if(Token::Macro) {
+            self.open_blocks.push(OpenBlock::Macro);
+            let Some(Token::Ident(name)) = self.next() else {
+                panic!("Expected macro name");
+            };
+            self.expect(Token::OParen).expect("Expected '(' after macro name");
+            let mut params = ArenaVec::new(self.arena);
+            while !self.next_if(Token::CParen) {
+                let Some(Token::Ident(param)) = self.next() else {
+                    panic!("Expected parameter name");
+                };
+                let default = self.next_if(Token::Equals).then(|| self.parse_ternary());
+                params.push((param, default));
+                if !self.next_if(Token::Comma) {
+                    self.expect(Token::CParen).expect("Missing closing paren");
+                    break;
+                }
+            }
+            Content::Block {
+                kind: Block::Macro { name, params },
+            }
         } else {
-            panic!("Expected if or for");
+            panic!("Expected if, for, with, match, block or macro");
         };
         self.ast.push(declaration);
     }
 
+    /// parses `ident = expr`
+    fn parse_assignment(&mut self) -> (Symbol<'a>, ExprRef<'a>) {
+        let Some(Token::Ident(ident)) = self.next() else {
+            panic!("Expected identifier to assign to");
+        };
+        self.expect(Token::Equals).expect("Expected '=' after identifier");
+        (ident, self.parse_ternary())
+    }
+
+    /// parses `ident = expr, ident = expr, ...`
+    fn parse_assignments(&mut self) -> ArenaVec<'a, (Symbol<'a>, ExprRef<'a>)> {
+        let mut assignments = ArenaVec::new(self.arena);
+        loop {
+            assignments.push(self.parse_assignment());
+            if !self.next_if(Token::Comma) {
+                break;
+            }
+        }
+        assignments
+    }
+
+    fn parse_binding(&mut self) -> Binding<'a> {
+        if self.next_if(Token::OBracket) {
+            let mut bindings = ArenaVec::new(self.arena);
+            loop {
+                bindings.push(self.parse_binding());
+                if !self.next_if(Token::Comma) {
+                    break;
+                }
+            }
+            self.expect(Token::CBracket).expect("Missing closing bracket");
+            return Binding::Array(bindings);
+        }
+
+        let Some(Token::Ident(ident)) = self.next() else {
+            panic!("Expected Identifier");
+        };
+        Binding::Ident(ident)
+    }
+
     fn parse_else_declaration(&mut self) {
+        let Some(&open_block) = self.open_blocks.last() else {
+            panic!("Found {{:else}} or {{:case}} outside of a block");
+        };
+        if !matches!(open_block, OpenBlock::If | OpenBlock::For | OpenBlock::Match) {
+            panic!("Only if, for and match blocks can be followed by {{:else}}");
+        }
         self.ast.push(Content::EndBlock);
-        self.expect(Token::Else).expect("Expected else statement");
+
+        if self.next_if(Token::Case) {
+            if open_block != OpenBlock::Match {
+                panic!("{{:case}} can only be used inside of a match block");
+            }
+            let mut values = ArenaVec::new(self.arena);
+            loop {
+                values.push(self.parse_ternary());
+                if !self.next_if(Token::Comma) {
+                    break;
+                }
+            }
+            self.ast.push(Content::Block { kind: Block::Case { values } });
+            return;
+        }
+        self.expect(Token::Else).expect("Expected else or case statement");
 
         let declaration = if self.next_if(Token::If) {
+            if matches!(open_block, OpenBlock::For | OpenBlock::Match) {
+                panic!("A for or match block can only be followed by a plain {{:else}}");
+            }
             Content::Block {
                 kind: Block::ElseIf {
-                    condition: self.parse_logical(),
+                    condition: self.parse_ternary(),
                 },
             }
         } else {
@@ -454,6 +694,31 @@ impl<'a, 's> Parser<'a, 's> {
                 idents.push(ident)
             }
             self.ast.push(Content::Keys(idents));
+        } else if self.next_if(Token::Let) {
+            let (ident, value) = self.parse_assignment();
+            self.ast.push(Content::Let { ident, value });
+        } else if self.next_if(Token::Include) {
+            let Some(Token::String(path)) = self.next() else {
+                panic!("@include statement needs to take in a string as argument. For example `@include \"./card.html\"");
+            };
+            let bindings = if self.next_if(Token::With) {
+                self.parse_assignments()
+            } else {
+                ArenaVec::new(self.arena)
+            };
+            self.ast.push(Content::Include { path: path.as_str(), bindings });
+        } else if self.next_if(Token::Import) {
+            let Some(Token::String(path)) = self.next() else {
+                panic!("@import statement needs to take in a string as argument. For example `@import \"./macros.html\"");
+            };
+            self.ast.push(Content::Import { path: path.as_str() });
+        } else if self.next_if(Token::Call) {
+            let call = self.parse_ternary();
+            if !matches!(*call, Expr::Function { .. }) {
+                panic!("@call statement needs to take in a macro call. For example `@call button(\"Save\")`");
+            }
+            self.open_blocks.push(OpenBlock::Call);
+            self.ast.push(Content::Block { kind: Block::Call { call } });
         } else if self.next_if(Token::Base) {
             if self.base_template.is_some() {
                 panic!("There may only be one @base statement per file")
@@ -464,6 +729,8 @@ impl<'a, 's> Parser<'a, 's> {
             };
 
             self.base_template = Some(PathBuf::from(path));
+        } else if self.next_if(Token::Delimiters) {
+            panic!("@delimiters may only appear at the very start of a file");
         }
     }
 
@@ -473,16 +740,27 @@ impl<'a, 's> Parser<'a, 's> {
         } else if self.next_if(Token::Colon) {
             self.parse_else_declaration();
         } else if self.next_if(Token::Slash) {
+            if self.open_blocks.pop().is_none() {
+                panic!("Found {{/}} without a block to close");
+            }
             self.ast.push(Content::EndBlock);
         } else if self.next_if(Token::At) {
             self.parse_statement();
         } else {
-            let expr = Content::Expression(self.parse_logical());
-            self.ast.push(expr);
+            let expr = self.parse_ternary();
+            // rendered in place by the template, so there's no value to use in anything bigger
+            if matches!(&*expr, Expr::Function { ident, .. } if matches!(ident.name(), "super" | "caller")) {
+                self.render_calls -= 1;
+            }
+            self.ast.push(Content::Expression(expr));
+        }
+
+        if self.render_calls > 0 {
+            panic!("super() and caller() can only be used on their own, like `{{super()}}`");
         }
     }
 
-    pub(crate) fn execute(mut self, content: Vec<DocumentKind<'a, 's>>) -> (Vec<Content<'a, 's>>, Option<PathBuf>) {
+    pub(crate) fn execute(mut self, content: Vec<DocumentKind<'a, 's>>) -> (ArenaVec<'a, Content<'a, 's>>, Option<PathBuf>) {
         content.into_iter().for_each(|thing| {
             if let DocumentKind::Markup(text) = thing {
                 self.ast.push(Content::Markup(text));
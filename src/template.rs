@@ -1,14 +1,131 @@
-use crate::parser::*;
-use std::{collections::HashMap, slice};
+use crate::{arena::{Arena, ArenaVec, OutOfMemory}, intern::{LOOP, SLOT, Symbol}, loader::{Document, LoadError, Loader, check_nesting}, parser::*};
+use std::{collections::HashMap, fmt, mem, path::{Path, PathBuf}, slice};
 
-pub(crate) type Environment<'a> = HashMap<&'a str, Value<'a>>;
+/// The value bound to each symbol, indexed by its id. Bindings made inside of a block shadow those outside of it,
+/// and are undone once the block ends.
+#[derive(Debug, Clone)]
+pub(crate) struct Environment<'a> {
+    slots: Vec<Option<Value<'a>>>,
+    /// the slot of each binding made in a block and what it replaced, to restore when the block ends
+    shadowed: Vec<(usize, Option<Value<'a>>)>,
+    /// how many bindings had been shadowed when each scope was pushed
+    scopes: Vec<usize>,
+}
+
+impl<'a> Environment<'a> {
+    pub fn new() -> Self {
+        Environment {
+            slots: Vec::new(),
+            shadowed: Vec::new(),
+            scopes: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, symbol: Symbol) -> Option<&Value<'a>> {
+        self.slots.get(symbol.id()).and_then(Option::as_ref)
+    }
+
+    /// binds `symbol` in the innermost scope
+    pub fn insert(&mut self, symbol: Symbol, value: Value<'a>) {
+        let id = symbol.id();
+        if id >= self.slots.len() {
+            self.slots.resize(id + 1, None);
+        }
+
+        let previous = self.slots[id].replace(value);
+        // nothing to restore once the global scope is done
+        if !self.scopes.is_empty() {
+            self.shadowed.push((id, previous));
+        }
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(self.shadowed.len());
+    }
+
+    pub fn pop_scope(&mut self) {
+        let Some(start) = self.scopes.pop() else {
+            panic!("Cannot pop the global scope");
+        };
+        self.shadowed.drain(start..).rev().for_each(|(id, previous)| self.slots[id] = previous);
+    }
+
+    /// how many scopes have been pushed
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    /// undoes the bindings of every scope past the first `depth`, until they are redone by `unhide_scopes`
+    pub fn hide_scopes(&mut self, depth: usize) -> HiddenScopes<'a> {
+        let start = self.scopes.get(depth).copied().unwrap_or(self.shadowed.len());
+        let scopes = self.scopes.split_off(depth);
+        let shadowed = self.shadowed.split_off(start);
+        let mut bound: Vec<_> = shadowed.iter().rev()
+            .map(|(id, previous)| mem::replace(&mut self.slots[*id], previous.clone()))
+            .collect();
+        bound.reverse();
+
+        HiddenScopes { scopes, shadowed, bound }
+    }
+
+    pub fn unhide_scopes(&mut self, hidden: HiddenScopes<'a>) {
+        let HiddenScopes { scopes, shadowed, bound } = hidden;
+        // scopes hold positions in `shadowed`, which only line up again once everything since has been popped
+        debug_assert_eq!(scopes.first().map_or(self.shadowed.len(), |&start| start), self.shadowed.len());
+        shadowed.iter().zip(bound).for_each(|((id, _), value)| self.slots[*id] = value);
+        self.scopes.extend(scopes);
+        self.shadowed.extend(shadowed);
+    }
+}
+
+/// The bindings taken out of an [`Environment`] by `hide_scopes`.
+pub(crate) struct HiddenScopes<'a> {
+    scopes: Vec<usize>,
+    shadowed: Vec<(usize, Option<Value<'a>>)>,
+    /// the value of each binding in `shadowed` right after it was made
+    bound: Vec<Option<Value<'a>>>,
+}
+
+/// a value, unless the arena being rendered into ran out of memory for it
+type Evaluated<'a> = Result<Value<'a>, OutOfMemory>;
+
+/// Why rendering stopped partway through.
+#[derive(Debug)]
+pub(crate) enum RenderError {
+    /// an included or imported template couldn't be loaded
+    Load(LoadError),
+    OutOfMemory(OutOfMemory),
+}
 
-fn evaluate_arithmetic<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>) -> Value<'a> {
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Load(err) => err.fmt(f),
+            RenderError::OutOfMemory(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl From<LoadError> for RenderError {
+    fn from(err: LoadError) -> Self {
+        RenderError::Load(err)
+    }
+}
+
+impl From<OutOfMemory> for RenderError {
+    fn from(err: OutOfMemory) -> Self {
+        RenderError::OutOfMemory(err)
+    }
+}
+
+fn evaluate_arithmetic<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
     use BinaryOp as Op;
     use Value::*;
-    let a = evaluate_expression(lhs, env).unwrap_number();
-    let b = evaluate_expression(rhs, env).unwrap_number();
-    match kind {
+    let a = evaluate_expression(lhs, env, arena)?.unwrap_number();
+    let b = evaluate_expression(rhs, env, arena)?.unwrap_number();
+    Ok(match kind {
         Op::Add => Number(a + b),
         Op::Subtract => Number(a - b),
         Op::Multiply => Number(a * b),
@@ -21,27 +138,27 @@ fn evaluate_arithmetic<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env:
         Op::LessThan => Boolean(a < b),
         Op::LessThanOrEquals => Boolean(a <= b),
         _ => unreachable!(),
-    }
+    })
 }
 
-fn evaluate_logic<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>) -> Value<'a> {
+fn evaluate_logic<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
     use BinaryOp as Op;
     use Value::*;
-    let a = evaluate_expression(lhs, env).unwrap_boolean();
-    let b = evaluate_expression(rhs, env).unwrap_boolean();
-    match kind {
+    let a = evaluate_expression(lhs, env, arena)?.unwrap_boolean();
+    let b = evaluate_expression(rhs, env, arena)?.unwrap_boolean();
+    Ok(match kind {
         Op::And => Boolean(a && b),
         Op::Or => Boolean(a || b),
         _ => unreachable!(),
-    }
+    })
 }
 
 #[allow(unused)]
-fn evaluate_concat<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>) -> Value<'a> {
+fn evaluate_concat<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
     use BinaryOp as Op;
     use Value::*;
-    // let a = evaluate_expression(lhs, env).clone_to_string();
-    // let b = evaluate_expression(rhs, env).clone_to_string();
+    // let a = evaluate_expression(lhs, env, arena).clone_to_string();
+    // let b = evaluate_expression(rhs, env, arena).clone_to_string();
     // match kind {
     //     Op::Concat => String(&(a + &b)),
     //     _ => unreachable!(),
@@ -49,127 +166,336 @@ fn evaluate_concat<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Env
     unimplemented!()
 }
 
-fn evaluate_index<'a>(lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>) -> Value<'a> {
-    let list = evaluate_expression(lhs, env).unwrap_array();
-    let index = evaluate_expression(rhs, env).unwrap_number();
+fn evaluate_index<'a>(lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
+    if let Value::Object(object) = evaluate_expression(lhs, env, arena)? {
+        let Value::String(key) = evaluate_expression(rhs, env, arena)? else {
+            panic!("Objects can only be indexed with strings");
+        };
+        return Ok(object.iter()
+            .find(|(k, _)| *k == key)
+            .map_or(Value::Null, |(_, value)| value.clone()));
+    }
+
+    let list = evaluate_expression(lhs, env, arena)?.unwrap_array();
+    let index = evaluate_expression(rhs, env, arena)?.unwrap_number();
     if index.is_sign_negative() {
         panic!("Cannot have negative index");
     }
-    list[index.trunc() as usize].clone()
+    Ok(list[index.trunc() as usize].clone())
 }
 
-fn evaluate_binary_op<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>) -> Value<'a> {
+fn evaluate_binary_op<'a>(kind: BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>, env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
     if kind.takes_in_numbers() {
-        return evaluate_arithmetic(kind, lhs, rhs, env);
+        return evaluate_arithmetic(kind, lhs, rhs, env, arena);
     }
     if kind.takes_in_booleans() {
-        return evaluate_logic(kind, lhs, rhs, env);
+        return evaluate_logic(kind, lhs, rhs, env, arena);
     }
     if kind.takes_in_strings() {
-        return evaluate_concat(kind, lhs, rhs, env);
+        return evaluate_concat(kind, lhs, rhs, env, arena);
     }
     if let BinaryOp::Index = kind {
-        return evaluate_index(lhs, rhs, env);
+        return evaluate_index(lhs, rhs, env, arena);
+    }
+    if let BinaryOp::Coalesce = kind {
+        return match evaluate_expression(lhs, env, arena)? {
+            Value::Null => evaluate_expression(rhs, env, arena),
+            value => Ok(value),
+        };
     }
     unreachable!()
 }
 
-fn evaluate_unary_op<'a>(kind: UnaryOp, value: &Expr<'a>, env: &Environment<'a>) -> Value<'a> {
+fn evaluate_unary_op<'a>(kind: UnaryOp, value: &Expr<'a>, env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
     use UnaryOp::*;
     match kind {
-        Dummy => return evaluate_expression(value, env),
+        Dummy => return evaluate_expression(value, env, arena),
         Not => {
-            let Value::Number(num) = evaluate_expression(value, env) else {
+            let Value::Number(num) = evaluate_expression(value, env, arena)? else {
                 panic!("Cannot not non booleans");
             };
-            return Value::Number(-num);
+            return Ok(Value::Number(-num));
         }
         Negate => {
-            let Value::Number(num) = evaluate_expression(value, env) else {
+            let Value::Number(num) = evaluate_expression(value, env, arena)? else {
                 panic!("Cannot negate non numbers");
             };
-            return Value::Number(-num);
+            return Ok(Value::Number(-num));
         }
     }
 }
 
-fn evaluate_function_call<'a>(ident: &str, args: &[ExprRef<'a>], env: &Environment<'a>) -> Value<'a> {
-    match ident {
+fn evaluate_function_call<'a>(ident: Symbol, args: &[ExprRef<'a>], env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
+    match ident.name() {
         "len" => {
             // make this better later
             assert_eq!(args.len(), 1);
-            let mut args = args.iter().map(|arg| evaluate_expression(arg, env));
-            if let Some(Value::Array(array)) = args.next() {
-                return Value::Number(array.len() as f32);
+            if let Value::Array(array) = evaluate_expression(&args[0], env, arena)? {
+                return Ok(Value::Number(array.len() as f32));
             } else {
                 panic!();
             }
         }
-        _ => panic!("Unrecognized function: {ident}"),
+        "range" => {
+            let mut args = args.iter().map(|arg| evaluate_expression(arg, env, arena).map(|arg| arg.unwrap_number()));
+            let (start, end) = match (args.next().transpose()?, args.next().transpose()?, args.next().transpose()?) {
+                (Some(end), None, None) => (0.0, end),
+                (Some(start), Some(end), None) => (start, end),
+                _ => panic!("range takes in either an end, or a start and an end"),
+            };
+
+            let (start, end) = (start.trunc() as i64, end.trunc() as i64);
+            let mut numbers = ArenaVec::try_with_capacity(arena, (end - start).max(0) as usize)?;
+            numbers.extend((start..end).map(|n| Value::Number(n as f32)));
+            Ok(Value::Array(numbers.into_slice()))
+        }
+        // macros render rather than evaluate to a value, so they only get this far as part of a bigger expression
+        _ => panic!("Unrecognized function: {ident}. Macros can only be called on their own, like `{{{ident}(...)}}`"),
     }
 }
 
-fn evaluate_expression<'a>(expr: &Expr<'a>, env: &Environment<'a>) -> Value<'a> {
+fn evaluate_expression<'a>(expr: &Expr<'a>, env: &Environment<'a>, arena: &'a Arena<'a>) -> Evaluated<'a> {
     match expr {
-        Expr::BinaryOp { kind, lhs, rhs } => evaluate_binary_op(*kind, lhs, rhs, env),
-        Expr::UnaryOp { kind, value } => evaluate_unary_op(*kind, value, env),
-        Expr::Value(Value::VarRef(ident)) => env.get(*ident).unwrap_or(&Value::Null).to_owned(),
-        Expr::Value(value) => value.to_owned(),
-        Expr::Function { ident, arguments } => evaluate_function_call(ident, arguments.as_ref(), env),
+        Expr::BinaryOp { kind, lhs, rhs } => evaluate_binary_op(*kind, lhs, rhs, env, arena),
+        Expr::UnaryOp { kind, value } => evaluate_unary_op(*kind, value, env, arena),
+        Expr::Value(Value::VarRef(ident)) => Ok(env.get(*ident).unwrap_or(&Value::Null).to_owned()),
+        Expr::Value(value) => Ok(value.to_owned()),
+        Expr::Function { ident, arguments } => evaluate_function_call(*ident, arguments.as_ref(), env, arena),
+        Expr::Array(elements) => {
+            let mut values = ArenaVec::try_with_capacity(arena, elements.len())?;
+            for element in elements {
+                values.push(evaluate_expression(element, env, arena)?);
+            }
+            Ok(Value::Array(values.into_slice()))
+        }
+        Expr::Object(entries) => {
+            let mut values = ArenaVec::try_with_capacity(arena, entries.len())?;
+            for (key, value) in entries {
+                values.push((*key, evaluate_expression(value, env, arena)?));
+            }
+            Ok(Value::Object(values.into_slice()))
+        }
+        Expr::Conditional { condition, then, otherwise } => {
+            if evaluate_expression(condition, env, arena)?.unwrap_boolean() {
+                evaluate_expression(then, env, arena)
+            } else {
+                evaluate_expression(otherwise, env, arena)
+            }
+        }
+    }
+}
+
+/// The named `{#block}`s of every template in an `@base` chain. Level 0 is the template that was rendered first,
+/// and each level after it is the base of the one before.
+pub(crate) struct Blocks<'a, 'b, 's> {
+    levels: Vec<HashMap<&'a str, slice::Iter<'b, Content<'a, 's>>>>,
+    /// the file each level was loaded from, which paths inside of it are relative to
+    paths: Vec<Option<PathBuf>>,
+}
+
+impl<'a, 'b, 's> Blocks<'a, 'b, 's> {
+    pub fn new(chain: &[(&'b [Content<'a, 's>], Option<PathBuf>)]) -> Self {
+        let levels = chain.iter().map(|(content, _)| {
+            content.iter().enumerate()
+                .filter_map(|(i, next)| match next {
+                    Content::Block { kind: Block::Named { name } } => Some((*name, content[i + 1..].iter())),
+                    _ => None,
+                })
+                .collect()
+        }).collect();
+        let paths = chain.iter().map(|(_, path)| path.clone()).collect();
+
+        Blocks { levels, paths }
+    }
+
+    /// the body of the first definition of `name` found within `levels`
+    fn find(&self, name: &str, mut levels: impl Iterator<Item = usize>) -> Option<(usize, slice::Iter<'b, Content<'a, 's>>)> {
+        levels.find_map(|level| self.levels[level].get(name).map(|body| (level, body.clone())))
+    }
+}
+
+/// Renders `chain`, a template followed by each `@base` it extends, with the output of each level available
+/// to the next as `slot`. Everything allocated while rendering goes into `arena`, which is reset first so that
+/// it can be reused from page to page, while only the parsed templates are kept around. Fails if an included
+/// template can't be loaded, or if `arena` reaches its limit.
+pub(crate) fn render_chain<'l>(
+    chain: &[(Document<'l>, Option<PathBuf>)],
+    env: &Environment<'_>,
+    loader: &Loader<'l>,
+    arena: &mut Arena<'_>,
+) -> Result<String, RenderError> {
+    arena.reset();
+    let arena = &*arena;
+    let mut env = env.clone();
+    let blocks = Blocks::new(chain);
+
+    let mut result = String::new();
+    for (level, (document, _)) in chain.iter().enumerate() {
+        if level > 0 {
+            env.insert(SLOT, Value::String(arena.try_alloc_str(&result)?));
+        }
+        result = Augment::new(document.iter(), &mut env, arena)
+            .with_blocks(&blocks, level)
+            .with_loader(loader)
+            .execute()?;
     }
+    Ok(result)
+}
+
+#[derive(Clone)]
+struct MacroDefinition<'a, 'b, 's> {
+    params: &'b [(Symbol<'a>, Option<ExprRef<'a>>)],
+    body: slice::Iter<'b, Content<'a, 's>>,
+    path: Option<PathBuf>,
 }
 
-pub struct Augment<'a, 'b, 's> {
+/// the body of a `{@call}` block, which renders where the block was, rather than inside of the macro
+#[derive(Clone)]
+struct Caller<'a, 'b, 's> {
+    body: slice::Iter<'b, Content<'a, 's>>,
+    path: Option<PathBuf>,
+    /// how many scopes the environment had when the block was entered
+    depth: usize,
+}
+
+/// `'l` is the lifetime of the loader's templates, which may outlive the arena being rendered into
+pub struct Augment<'a, 'b, 's, 'l> {
     iter: slice::Iter<'b, Content<'a, 's>>,
     result: String,
     env: &'b mut Environment<'a>,
+    arena: &'a Arena<'a>,
+    blocks: Option<&'b Blocks<'a, 'b, 's>>,
+    level: usize,
+    loader: Option<&'b Loader<'l>>,
+    /// the file being rendered, which includes and imports are relative to
+    path: Option<PathBuf>,
+    /// the files being included, starting with the one rendered
+    includes: Vec<PathBuf>,
+    /// the name and level of each named block currently being rendered, for `super()`
+    block_stack: Vec<(&'a str, usize)>,
+    macros: HashMap<Symbol<'a>, MacroDefinition<'a, 'b, 's>>,
+    /// the body of each `{@call}` block whose macro is currently being rendered, for `caller()`
+    callers: Vec<Caller<'a, 'b, 's>>,
 }
 
-impl<'a, 'b, 's> Augment<'a, 'b, 's> {
-    pub fn new(iter: slice::Iter<'b, Content<'a, 's>>, env: &'b mut Environment<'a>) -> Self {
+impl<'l: 'a, 'a: 's, 'b, 's> Augment<'a, 'b, 's, 'l> {
+    pub fn new(iter: slice::Iter<'b, Content<'a, 's>>, env: &'b mut Environment<'a>, arena: &'a Arena<'a>) -> Self {
         Self {
             iter,
             result: String::with_capacity(2048),
             env,
+            arena,
+            blocks: None,
+            level: 0,
+            loader: None,
+            path: None,
+            includes: Vec::new(),
+            block_stack: Vec::new(),
+            macros: HashMap::new(),
+            callers: Vec::new(),
         }
     }
 
-    pub fn execute(mut self) -> String {
-        self.augment();
-        return self.result
+    /// renders the template at `level` of an `@base` chain, so that its named blocks may be overridden
+    pub fn with_blocks(mut self, blocks: &'b Blocks<'a, 'b, 's>, level: usize) -> Self {
+        self.blocks = Some(blocks);
+        self.level = level;
+        self.path = blocks.paths[level].clone();
+        self
+    }
+
+    #[allow(unused)]
+    pub fn with_path(mut self, path: Option<PathBuf>) -> Self {
+        self.path = path;
+        self
     }
 
-    fn augment(&mut self) {
+    /// allows `{@include}` to load other templates
+    pub fn with_loader(mut self, loader: &'b Loader<'l>) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    /// fails if the arena runs out of memory, which leaves whatever was rendered so far behind
+    pub fn execute(mut self) -> Result<String, RenderError> {
+        self.includes.extend(self.path.clone());
+        self.augment()?;
+        Ok(self.result)
+    }
+
+    fn augment(&mut self) -> Result<(), RenderError> {
         use crate::parser::Block::*;
         use crate::parser::Content::*;
 
         let mut last_if_result = false;
+        let mut match_subject = Value::Null;
         while let Some(next) = self.iter.next() {
             match next {
                 Markup(content) => self.result.push_str(content),
-                Expression(expr) => evaluate_expression(expr, self.env).write_to(&mut self.result),
+                Expression(expr) if matches!(&**expr, Expr::Function { ident, .. } if ident.name() == "super") => self.augment_super()?,
+                Expression(expr) if matches!(&**expr, Expr::Function { ident, .. } if ident.name() == "caller") => self.augment_caller()?,
+                Expression(expr) if matches!(&**expr, Expr::Function { ident, .. } if self.macros.contains_key(ident)) => {
+                    let Expr::Function { ident, arguments } = &**expr else { unreachable!() };
+                    self.augment_macro(*ident, arguments.as_ref(), None)?;
+                }
+                Expression(expr) => evaluate_expression(expr, self.env, self.arena)?.write_to(&mut self.result),
 
-                Block { kind: If {..} } => last_if_result = self.augment_if(next),
+                Block { kind: If {..} } => last_if_result = self.augment_if(next)?,
                 Block { kind: Else | ElseIf {..} } if last_if_result => self.skip_block(),
-                Block { kind: Else | ElseIf {..} } => last_if_result = self.augment_if(next),
-                Block { kind: For { element, iterable } } => self.augment_for(element, iterable),
-                EndBlock => return,
+                Block { kind: Else | ElseIf {..} } => last_if_result = self.augment_if(next)?,
+                Block { kind: For { key, element, iterable } } => last_if_result = self.augment_for(key.as_ref(), element, iterable)?,
+                Block { kind: With { bindings } } => self.augment_with(bindings.as_ref())?,
+                Block { kind: Match { subject } } => {
+                    // anything before the first case is ignored
+                    match_subject = evaluate_expression(subject, self.env, self.arena)?;
+                    last_if_result = false;
+                    self.skip_block();
+                }
+                Block { kind: Named { name } } => self.augment_named(name)?,
+                Block { kind: Macro { name, params } } => {
+                    let definition = MacroDefinition { params: params.as_ref(), body: self.iter.clone(), path: self.path.clone() };
+                    self.macros.insert(*name, definition);
+                    self.skip_block();
+                }
+                Block { kind: Call { call } } => {
+                    let Expr::Function { ident, arguments } = &**call else { unreachable!() };
+                    let caller = Caller { body: self.iter.clone(), path: self.path.clone(), depth: self.env.depth() };
+                    self.skip_block();
+                    self.augment_macro(*ident, arguments.as_ref(), Some(caller))?;
+                }
+                Block { kind: Case {..} } if last_if_result => self.skip_block(),
+                Block { kind: Case { values } } => last_if_result = self.augment_case(&match_subject, values.as_ref())?,
+                EndBlock => return Ok(()),
 
+                Include { path, bindings } => self.augment_include(path, bindings.as_ref())?,
+                Import { path } => self.import_macros(path)?,
+                Let { ident, value } => {
+                    let value = evaluate_expression(value, self.env, self.arena)?;
+                    self.env.insert(*ident, value);
+                }
                 Keys(idents) => {
                     idents.iter().enumerate().for_each(|(i, ident)| {
-                        self.env.insert(ident, Value::Number(i as f32));
+                        self.env.insert(*ident, Value::Number(i as f32));
                     });
                 }
             }
         }
+        Ok(())
+    }
+
+    /// augments the body of a block in its own scope
+    fn augment_scoped(&mut self) -> Result<(), RenderError> {
+        self.env.push_scope();
+        let result = self.augment();
+        self.env.pop_scope();
+        result
     }
 
     fn skip_block(&mut self) {
         use crate::parser::Content::*;
         
         let mut nesting_level = 0;
-        while let Some(next) = self.iter.next() {
-            eprintln!("skipping on: {next:?}");
+        for next in self.iter.by_ref() {
             match next {
                 Block {..} => nesting_level += 1,
                 EndBlock if nesting_level == 0 => return,
@@ -182,57 +508,593 @@ impl<'a, 'b, 's> Augment<'a, 'b, 's> {
         panic!("missing closing block");
     }
 
-    fn augment_if(&mut self, next: &'b Content<'a, 's>) -> bool {
+    fn augment_if(&mut self, next: &'b Content<'a, 's>) -> Result<bool, RenderError> {
         use crate::parser::Block::*;
         use crate::parser::Content::*;
         
         match next {
             Block { kind: If { condition } | ElseIf { condition } } => {
-                eprintln!("if {condition:?}");
-                let condition = evaluate_expression(condition, self.env).unwrap_boolean();
-                eprintln!("evaluates to ... {condition:?}");
+                let condition = evaluate_expression(condition, self.env, self.arena)?.unwrap_boolean();
                 if condition { 
-                    self.augment();
-                    true
+                    self.augment_scoped()?;
+                    Ok(true)
                 } else { 
                     self.skip_block();
-                    false 
+                    Ok(false)
                 }
             }
 
             Block { kind: Else } => {
-                self.augment();
-                true
+                self.augment_scoped()?;
+                Ok(true)
             }
 
             _ => unreachable!(),
         }
     }
 
-    fn augment_for(&mut self, element: &Value<'a>, iterable: &Value<'a>) {
+    fn augment_case(&mut self, subject: &Value<'a>, values: &[ExprRef<'a>]) -> Result<bool, RenderError> {
+        for value in values {
+            if evaluate_expression(value, self.env, self.arena)? == *subject {
+                self.augment_scoped()?;
+                return Ok(true);
+            }
+        }
+        self.skip_block();
+        Ok(false)
+    }
+
+    fn augment_named(&mut self, name: &'a str) -> Result<(), RenderError> {
+        let Some(blocks) = self.blocks else {
+            return self.augment_scoped();
+        };
+
+        let body = self.iter.clone();
+        self.skip_block();
+        // a template further up the chain decides where this block goes
+        if blocks.find(name, self.level + 1..blocks.levels.len()).is_some() {
+            return Ok(());
+        }
+
+        match blocks.find(name, 0..=self.level) {
+            Some((level, body)) => self.augment_definition(name, level, body),
+            // like one in a macro imported from outside of the chain, which nothing can override
+            None => {
+                let iter = mem::replace(&mut self.iter, body);
+                let result = self.augment_scoped();
+                self.iter = iter;
+                result
+            }
+        }
+    }
+
+    /// renders the definition of the named block being rendered, that the current one overrides
+    fn augment_super(&mut self) -> Result<(), RenderError> {
+        let Some(&(name, level)) = self.block_stack.last() else {
+            panic!("super() can only be used inside of a named block");
+        };
+        let Some(blocks) = self.blocks else {
+            panic!("super() can only be used inside of a named block that overrides another");
+        };
+        let Some((level, body)) = blocks.find(name, level + 1..=self.level) else {
+            panic!("Block {name} does not override anything, so it cannot call super()");
+        };
+        self.augment_definition(name, level, body)
+    }
+
+    fn augment_definition(&mut self, name: &'a str, level: usize, body: slice::Iter<'b, Content<'a, 's>>) -> Result<(), RenderError> {
+        let iter = mem::replace(&mut self.iter, body);
+        let path = mem::replace(&mut self.path, self.blocks.unwrap().paths[level].clone());
+        self.block_stack.push((name, level));
+        let result = self.augment_scoped();
+        self.block_stack.pop();
+        self.iter = iter;
+        self.path = path;
+        result
+    }
+
+    /// loads the template that `path` refers to from the one being rendered
+    fn load(&self, path: &str) -> Result<(Document<'l>, PathBuf), LoadError> {
+        let Some(loader) = self.loader else {
+            panic!("Cannot load {path} without a loader");
+        };
+        let resolved = loader.resolve(Path::new(path), self.path.as_deref())?;
+        match loader.load(&resolved)? {
+            (_, Some(_)) => panic!("{} cannot have a @base statement, as it is included", resolved.display()),
+            (document, None) => Ok((document, resolved)),
+        }
+    }
+
+    fn augment_include(&mut self, path: &str, bindings: &[(Symbol<'a>, ExprRef<'a>)]) -> Result<(), RenderError> {
+        let (document, resolved) = self.load(path)?;
+        check_nesting(&self.includes, &resolved)?;
+
+        // included templates aren't part of the chain, so their blocks always render in place
+        let blocks = self.blocks.take();
+        self.includes.push(resolved.clone());
+        let iter = mem::replace(&mut self.iter, document.iter());
+        let path = self.path.replace(resolved);
+        let result = self.augment_with(bindings);
+        self.iter = iter;
+        self.path = path;
+        self.includes.pop();
+        self.blocks = blocks;
+        result
+    }
+
+    fn import_macros(&mut self, path: &str) -> Result<(), RenderError> {
+        let (document, resolved) = self.load(path)?;
+
+        document.iter().enumerate().for_each(|(i, next)| {
+            if let Content::Block { kind: Block::Macro { name, params } } = next {
+                let definition = MacroDefinition { params: params.as_ref(), body: document[i + 1..].iter(), path: Some(resolved.clone()) };
+                self.macros.insert(*name, definition);
+            }
+        });
+        Ok(())
+    }
+
+    fn augment_macro(&mut self, name: Symbol<'a>, arguments: &[ExprRef<'a>], caller: Option<Caller<'a, 'b, 's>>) -> Result<(), RenderError> {
+        let Some(MacroDefinition { params, body, path }) = self.macros.get(&name).cloned() else {
+            panic!("Unrecognized macro: {name}");
+        };
+        if arguments.len() > params.len() {
+            panic!("Macro {name} takes in at most {} arguments, but was given {}", params.len(), arguments.len());
+        }
+
+        let arguments = arguments.iter()
+            .map(|argument| evaluate_expression(argument, self.env, self.arena))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.env.push_scope();
+        let result = self.augment_macro_body(params, &arguments, body, path, caller);
+        self.env.pop_scope();
+        result
+    }
+
+    /// binds the parameters of a macro in the scope pushed for it, then renders its body
+    fn augment_macro_body(
+        &mut self,
+        params: &[(Symbol<'a>, Option<ExprRef<'a>>)],
+        arguments: &[Value<'a>],
+        body: slice::Iter<'b, Content<'a, 's>>,
+        path: Option<PathBuf>,
+        caller: Option<Caller<'a, 'b, 's>>,
+    ) -> Result<(), RenderError> {
+        for (i, (param, default)) in params.iter().enumerate() {
+            let value = match (arguments.get(i), default) {
+                (Some(argument), _) => argument.clone(),
+                (None, Some(default)) => evaluate_expression(default, self.env, self.arena)?,
+                (None, None) => Value::Null,
+            };
+            self.env.insert(*param, value);
+        }
+
+        let has_caller = caller.is_some();
+        self.callers.extend(caller);
+        let iter = mem::replace(&mut self.iter, body);
+        let path = mem::replace(&mut self.path, path);
+        let result = self.augment();
+        self.iter = iter;
+        self.path = path;
+        if has_caller {
+            self.callers.pop();
+        }
+        result
+    }
+
+    /// renders the body of the `{@call}` block with the variables and path of the template that called the macro
+    fn augment_caller(&mut self) -> Result<(), RenderError> {
+        let Some(caller) = self.callers.pop() else {
+            panic!("caller() can only be used inside of a macro invoked with @call");
+        };
+        let hidden = self.env.hide_scopes(caller.depth);
+        let iter = mem::replace(&mut self.iter, caller.body.clone());
+        let path = mem::replace(&mut self.path, caller.path.clone());
+        let result = self.augment_scoped();
+        self.iter = iter;
+        self.path = path;
+        self.env.unhide_scopes(hidden);
+        self.callers.push(caller);
+        result
+    }
+
+    fn augment_with(&mut self, bindings: &[(Symbol<'a>, ExprRef<'a>)]) -> Result<(), RenderError> {
+        self.env.push_scope();
+        let result = self.bind_all(bindings).and_then(|()| self.augment());
+        self.env.pop_scope();
+        result
+    }
+
+    fn bind_all(&mut self, bindings: &[(Symbol<'a>, ExprRef<'a>)]) -> Result<(), RenderError> {
+        for (ident, value) in bindings {
+            let value = evaluate_expression(value, self.env, self.arena)?;
+            self.env.insert(*ident, value);
+        }
+        Ok(())
+    }
+
+    /// returns whether the body was rendered at all, so that an `{:else}` following an empty loop is rendered instead
+    fn augment_for(&mut self, key: Option<&Binding<'a>>, element: &Binding<'a>, iterable_expr: &Expr<'a>) -> Result<bool, RenderError> {
         let body = self.iter.clone();
 
-        let Value::VarRef(iteration_var) = element else { unreachable!() };
-        if self.env.contains_key(*iteration_var) {
-            panic!("Cannot iterate with variable {iteration_var} because it has already been defined");
+        let iterable = evaluate_expression(iterable_expr, self.env, self.arena)?;
+        let length = match iterable {
+            Value::Array(array) => array.len(),
+            Value::Object(object) => object.len(),
+            Value::Null => match iterable_expr {
+                Expr::Value(Value::VarRef(symbol)) => panic!("Cannot iterate over {} because it has not been defined", symbol.name()),
+                _ => panic!("Cannot iterate over null"),
+            },
+            value => panic!("Cannot iterate over {value:?} because it is not an array or object"),
+        };
+
+        if length == 0 {
+            self.skip_block();
+            return Ok(false);
+        }
+
+        for i in 0..length {
+            let (index, value) = match iterable {
+                Value::Array(array) => (Value::Number(i as f32), array[i].clone()),
+                Value::Object(object) => (Value::String(object[i].0), object[i].1.clone()),
+                _ => unreachable!(),
+            };
+
+            // allocated before the scope is pushed, so that running out of memory can't leave it behind
+            let value = match (key, &iterable) {
+                // without a key, objects are iterated over as `[key, value]` pairs
+                (None, Value::Object(_)) => Value::Array(self.arena.try_alloc_slice(&[index.clone(), value])?),
+                _ => value,
+            };
+            let metadata = self.loop_metadata(i, length)?;
+
+            self.env.push_scope();
+            if let Some(key) = key {
+                self.bind(key, index);
+            }
+            self.bind(element, value);
+            self.env.insert(LOOP, metadata);
+            self.iter = body.clone();
+            let result = self.augment();
+            self.env.pop_scope();
+            result?;
+        }
+        Ok(true)
+    }
+
+    /// binds `value` to the variable(s) in `binding`, in the innermost scope
+    fn bind(&mut self, binding: &Binding<'a>, value: Value<'a>) {
+        match binding {
+            Binding::Ident(ident) => self.env.insert(*ident, value),
+            Binding::Array(bindings) => {
+                let Value::Array(array) = value else {
+                    panic!("Cannot destructure {value:?} because it is not an array");
+                };
+                bindings.iter().enumerate().for_each(|(i, binding)| {
+                    self.bind(binding, array.get(i).cloned().unwrap_or(Value::Null));
+                });
+            }
         }
+    }
+
+    /// the `loop` object available inside of a for loop
+    fn loop_metadata(&self, index: usize, length: usize) -> Evaluated<'a> {
+        let index1 = index + 1;
+        let odd = index1 % 2 == 1;
+        Ok(Value::Object(self.arena.try_alloc_slice(&[
+            ("index", Value::Number(index as f32)),
+            ("index1", Value::Number(index1 as f32)),
+            ("first", Value::Boolean(index == 0)),
+            ("last", Value::Boolean(index1 == length)),
+            ("length", Value::Number(length as f32)),
+            ("odd", Value::Boolean(odd)),
+            ("even", Value::Boolean(!odd)),
+        ])?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{arena, intern::Interner, lexer::Lexer};
+    use super::*;
+
+    const ARENA_SIZE: usize = 8 * 1024;
 
-        let Value::VarRef(iter_ident) = iterable else { unreachable!() };
-        let iterable = self.env.get(*iter_ident).unwrap_or_else(|| {
-            panic!("Cannot iterate with variable {iter_ident} because it has not been defined");
+    fn render<'a>(contents: &'a str, arena: &'a Arena<'a>, interner: &'a Interner<'a>, env: &mut Environment<'a>) -> String {
+        let lexer = Lexer::new(contents, arena, interner);
+        let (ast, _) = Parser::new(arena).execute(lexer.execute());
+        Augment::new(ast.iter(), env, arena).execute().unwrap()
+    }
+
+    /// renders each template in `chain` with the output of the previous one as `slot`, like a chain of `@base`s
+    fn render_chain<'a>(chain: &[&'a str], arena: &'a Arena<'a>, interner: &'a Interner<'a>, env: &mut Environment<'a>) -> String {
+        let loader = Loader::new(arena, interner, Default::default());
+        let chain: Vec<_> = chain.iter().map(|contents| (loader.parse(contents).0, None)).collect();
+        super::render_chain(&chain, env, &loader, &mut Arena::new(ARENA_SIZE)).unwrap()
+    }
+
+    /// A directory of templates for a test, which is removed once the test is done with it.
+    struct TestDir {
+        path: PathBuf,
+    }
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("augment_{name}"));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TestDir { path }
+        }
+
+        fn with_file(self, name: &str, contents: &str) -> Self {
+            let path = self.path.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+            self
+        }
+
+        /// renders `chain` as if each template in it was `page.html` in this directory, with `shared` as a template directory
+        fn render(&self, chain: &[&str]) -> Result<String, RenderError> {
+            let arena = arena::Arena::new(ARENA_SIZE);
+            let interner = Interner::new(&arena);
+            let loader = Loader::new(&arena, &interner, Default::default()).with_template_dirs(vec![self.path.join("shared")]);
+            let chain: Vec<_> = chain.iter().map(|contents| (loader.parse(contents).0, Some(self.path.join("page.html")))).collect();
+            super::render_chain(&chain, &Environment::new(), &loader, &mut Arena::new(ARENA_SIZE))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn exposes_loop_metadata() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        env.insert(interner.intern("xs"), Value::Array(arena.alloc_slice(&[Value::Number(4.0), Value::Number(5.0), Value::Number(6.0)])));
+        let contents = "{#for x in xs}{loop.index}{loop[\"index1\"]}{loop.length}{loop.first}{loop.last}{loop.odd} {/}";
+        assert_eq!(
+            render(contents, &arena, &interner, &mut env),
+            "013truefalsetrue 123falsefalsefalse 233falsetruetrue ",
+        );
+        assert!(env.get(LOOP).is_none());
+    }
+
+    #[test]
+    fn renders_else_for_empty_loops() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        env.insert(interner.intern("empty"), Value::Array(&[]));
+        env.insert(interner.intern("xs"), Value::Array(arena.alloc_slice(&[Value::Number(1.0), Value::Number(2.0)])));
+        let contents = "{#for x in empty}{x}{:else}none{/}, {#for x in xs}{x}{:else}none{/}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "none, 12");
+    }
+
+    #[test]
+    fn iterates_over_expressions() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        let posts = arena.alloc_slice(&[Value::String("a"), Value::String("b")]);
+        let user = arena.alloc_slice(&[("posts", Value::Array(posts))]);
+        env.insert(interner.intern("user"), Value::Object(user));
+        let contents = "{#for p in user.posts}{p}{/} {#for n in range(1, 4)}{n}{/} {#for n in range(len(user[\"posts\"]))}{n}{/}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "ab 123 01");
+    }
+
+    #[test]
+    fn loop_variables_are_scoped_to_their_block() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        env.insert(interner.intern("x"), Value::String("outer"));
+        let contents = "{#for x in range(2)}{#for x in range(2)}{x}{/}{x} {/}{x}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "010 011 outer");
+    }
+
+    #[test]
+    fn restores_shadowed_variables() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        let contents = "{@let x = 1}{#with x = 2}{@let x = 3}{@let y = 4}{x}{y}{/}{x}{y}{@let x = 5}{x}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "341null5");
+    }
+
+    #[test]
+    fn destructures_loop_elements() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        let users = arena.alloc_slice(&[
+            Value::Array(arena.alloc_slice(&[Value::Number(1.0), Value::String("ann")])),
+            Value::Array(arena.alloc_slice(&[Value::Number(2.0), Value::String("bob")])),
+        ]);
+        env.insert(interner.intern("users"), Value::Array(users));
+        env.insert(interner.intern("settings"), Value::Object(arena.alloc_slice(&[("a", Value::Number(1.0)), ("b", Value::Boolean(true))])));
+
+        let contents = "{#for [id, name] in users}{id}:{name} {/}{#for i, [id] in users}{i}{id} {/}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "1:ann 2:bob 01 12 ");
+
+        let contents = "{#for key, value in settings}{key}={value} {/}{#for [key, value] in settings}{key}={value} {/}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "a=1 b=true a=1 b=true ");
+    }
+
+    #[test]
+    fn assigns_local_variables() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        env.insert(interner.intern("price"), Value::Number(3.0));
+        let contents = "{@let total = price * 2}{total} {#with total = total + 1, double = total * 2}{total} {double} {/}{total}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "6 7 14 6");
+
+        let contents = "{#for n in range(2)}{@let m = n * 10}{m} {/}{m}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "0 10 null");
+    }
+
+    #[test]
+    fn evaluates_conditional_and_coalesce() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        env.insert(interner.intern("active"), Value::Boolean(true));
+        env.insert(interner.intern("count"), Value::Number(3.0));
+        let contents = "{active ? \"on\" : \"off\"} {title ?? \"Untitled\"} {count ?? 0} {count > 5 ? 1 : count > 2 ? 2 : 3}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "on Untitled 3 2");
+    }
+
+    #[test]
+    fn evaluates_array_and_object_literals() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        env.insert(interner.intern("status"), Value::String("open"));
+        let contents = "{#for n in [1, 2, 1 + 2,]}{n}{/} {len([])} {{open: \"green\", \"closed\": \"red\"}[status]} {[[1], {a: [4]}][1].a[0]}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "123 0 green 4");
+    }
+
+    #[test]
+    fn renders_matching_case() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        let contents = "{#for status in [\"open\", \"merged\", \"draft\"]}{#match status}\n  {:case \"open\"}O{:case \"closed\", \"merged\"}C{:else}?{/}{/}";
+        assert_eq!(render(contents, &arena, &interner, &mut env), "OC?");
+    }
+
+    #[test]
+    fn overrides_named_blocks() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        let page = "{#block title}Page - {super()}{/}{#block sidebar}links{/}body";
+        let section = "{#block title}Section{/}<main>{slot}</main>{#block sidebar}{/}";
+        let site = "<title>{#block title}Site{/}</title>{slot}{#block scripts}<script>{/}";
+        assert_eq!(
+            render_chain(&[page, section, site], &arena, &interner, &mut env),
+            "<title>Page - Section</title><main>body</main>links<script>",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be used on their own")]
+    fn rejects_super_inside_of_expressions() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let mut env = Environment::new();
+        render("{#block title}{true ? super() : \"\"}{/}", &arena, &interner, &mut env);
+    }
+
+    #[test]
+    fn includes_other_templates() {
+        let dir = TestDir::new("includes_other_templates").with_file("card.html", "<{item ?? name}>");
+        let page = "{@let name = \"default\"}{@include \"card.html\"}{#for post in [1, 2]}{@include \"card.html\" with item = post}{/}{item}";
+        assert_eq!(dir.render(&[page]).unwrap(), "<default><1><2>null");
+    }
+
+    #[test]
+    fn renders_blocks_of_included_templates_in_place() {
+        let dir = TestDir::new("renders_blocks_of_included_templates_in_place").with_file("card.html", "<{#block body}card{/}>");
+        assert_eq!(dir.render(&["page {@include \"card.html\"}"]).unwrap(), "page <card>");
+        // even when the chain has blocks of the same name, overridden below or above the include
+        assert_eq!(dir.render(&["{#block body}page{/}", "[{#block body}layout{/}]{@include \"card.html\"}"]).unwrap(), "[page]<card>");
+        assert_eq!(dir.render(&["{@include \"card.html\"}", "[{#block body}layout{/}]{slot}"]).unwrap(), "[layout]<card>");
+    }
+
+    #[test]
+    fn resolves_paths_relative_to_the_template() {
+        let dir = TestDir::new("resolves_paths_relative_to_the_template")
+            .with_file("partials/card.html", "[{@include \"badge.html\"}]")
+            .with_file("partials/badge.html", "badge")
+            .with_file("shared/footer.html", "footer");
+        assert_eq!(dir.render(&["{@include \"partials/card.html\"}{@include \"footer.html\"}"]).unwrap(), "[badge]footer");
+
+        let Err(RenderError::Load(missing)) = dir.render(&["{@include \"missing.html\"}"]) else {
+            panic!("Expected missing.html not to be found");
+        };
+        let message = missing.to_string();
+        assert!(message.starts_with("Could not find template missing.html"));
+        assert!(message.contains(&dir.path.join("missing.html").display().to_string()));
+        assert!(message.contains(&dir.path.join("shared/missing.html").display().to_string()));
+    }
+
+    #[test]
+    fn renders_macros() {
+        let dir = TestDir::new("renders_macros")
+            .with_file("macros.html", "{#macro card(title)}<div>{title}: {caller()}</div>{/}");
+        let page = "{#macro button(label, kind = \"primary\")}<{kind}>{label}{/}{button(\"Save\")}{button(\"No\", \"danger\")}\
+            {@import \"macros.html\"}{@call card(\"Hi\")}{button(\"Ok\")}{/}{label}";
+        assert_eq!(dir.render(&[page]).unwrap(), "<primary>Save<danger>No<div>Hi: <primary>Ok</div>null");
+    }
+
+    #[test]
+    fn renders_caller_bodies_in_the_calling_template() {
+        let dir = TestDir::new("renders_caller_bodies_in_the_calling_template")
+            .with_file("macros/card.html", "{#macro card(title)}{@let inner = 1}{title}|{caller()}{/}")
+            .with_file("macros/badge.html", "wrong badge")
+            .with_file("badge.html", "badge");
+        let page = "{@import \"macros/card.html\"}{@let title = \"outer\"}\
+            {@call card(\"inner\")}{title} {inner} {@include \"badge.html\"}{/} {title}";
+        assert_eq!(dir.render(&[page]).unwrap(), "inner|outer null badge outer");
+    }
+
+    #[test]
+    fn fails_on_include_cycles() {
+        let dir = TestDir::new("fails_on_include_cycles")
+            .with_file("a.html", "{@include \"b.html\"}")
+            .with_file("b.html", "{@include \"a.html\"}");
+        let result = dir.render(&["{@include \"a.html\"}"]);
+        assert!(matches!(result, Err(RenderError::Load(LoadError::Cycle { .. }))));
+    }
+
+    #[test]
+    fn fails_on_deeply_nested_includes() {
+        let dir = (0..=crate::loader::MAX_DEPTH).fold(TestDir::new("fails_on_deeply_nested_includes"), |dir, i| {
+            dir.with_file(&format!("{i}.html"), &format!("{{@include \"{}.html\"}}", i + 1))
         });
-        let Value::Array(ref array) = iterable.clone() else {
-            panic!("Cannot iterate with variable {iter_ident} because it is not an array",);
+        let result = dir.render(&["{@include \"0.html\"}"]);
+        assert!(matches!(result, Err(RenderError::Load(LoadError::TooDeep { .. }))));
+    }
+
+    #[test]
+    fn fails_once_the_arena_runs_out_of_memory() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let loader = Loader::new(&arena, &interner, Default::default());
+        let chain = [(loader.parse("{#for x in range(100000)}{x}{/}").0, None)];
+        let env = Environment::new();
+
+        let mut scratch = Arena::new(64).with_limit(4096);
+        let Err(RenderError::OutOfMemory(err)) = super::render_chain(&chain, &env, &loader, &mut scratch) else {
+            panic!("Expected rendering to run out of memory");
         };
+        assert_eq!(err.limit, 4096);
+        assert!(err.requested >= 100000 * size_of::<Value>());
+        assert!(scratch.allocated() <= 4096);
+    }
 
-        self.env.insert(iteration_var, Value::Null);
-        array
+    #[test]
+    fn renders_layouts_into_their_own_arena() {
+        let arena = arena::Arena::new(ARENA_SIZE);
+        let interner = Interner::new(&arena);
+        let loader = Loader::new(&arena, &interner, Default::default());
+        let chain: Vec<_> = ["{@let n = [1, 2, 3]}page {n[0]}", "<section>{slot}</section>", "<body>{slot}</body>"]
             .iter()
-            .map(|value| {
-                *self.env.get_mut(*iteration_var).unwrap() = value.clone();
-                self.iter = body.clone();
-                self.augment()
-            })
-            .collect()
+            .map(|contents| (loader.parse(contents).0, None))
+            .collect();
+        let mut env = Environment::new();
+        env.insert(SLOT, Value::String(""));
+
+        // far more output than fits in the arena, if the slots of every render were kept
+        let mut scratch = Arena::new(64).with_limit(1024);
+        for _ in 0..1000 {
+            let result = super::render_chain(&chain, &env, &loader, &mut scratch).unwrap();
+            assert_eq!(result, "<body><section>page 1</section></body>");
+        }
     }
 }